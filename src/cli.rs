@@ -0,0 +1,152 @@
+//! Command line interface definition.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use winbindex_differ::diff_config::Age;
+use winbindex_differ::git_utils::Snapshot;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Continuous Ghidriff diffs of Windows binaries tracked by Winbindex")]
+pub struct Cli {
+    /// Path to the diffing configuration file.
+    #[arg(short, long, global = true, default_value = "config.yaml")]
+    pub config: PathBuf,
+    /// Profile from the configuration file's `profiles:` to apply, defaults to
+    /// `$WINBINDEX_DIFFER_PROFILE`.
+    #[arg(short, long, global = true)]
+    pub profile: Option<String>,
+    /// Overrides `store_dir` from the configuration file.
+    #[arg(long, global = true)]
+    pub store_dir: Option<PathBuf>,
+    /// Overrides `repo_dir` from the configuration file.
+    #[arg(long, global = true)]
+    pub repo_dir: Option<PathBuf>,
+    /// Use the existing clones without fetching, failing if a branch has not been cloned.
+    #[arg(long, global = true)]
+    pub offline: bool,
+    /// Only fetch repositories last synced longer ago than this, ie. `6h`.
+    #[arg(long, global = true, value_name = "AGE")]
+    pub max_repo_age: Option<Age>,
+    /// Read every branch's metadata as of this commit or UTC date, ie. `2024-03-12`, instead of
+    /// the tip of the branch. Overrides `at` from the configuration file.
+    #[arg(long, global = true, value_name = "COMMIT|DATE")]
+    pub at: Option<Snapshot>,
+    /// Increase logging verbosity, may be repeated.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Only log errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Write a starter config file to the `--config` path.
+    Init,
+    /// Rewrite the config file in the current layout version, keeping a backup of the original.
+    Migrate,
+    /// Check the config file for mistakes, including against the cloned repositories.
+    Validate,
+    /// Clone or pull all configured Winbindex repositories.
+    Sync,
+    /// Update repositories and diff every new version of the configured binaries.
+    Run {
+        /// Re-evaluate every binary, not just those whose metadata changed since the last run.
+        #[arg(long)]
+        full: bool,
+    },
+    /// Print the diffs `run` would perform, without downloading anything or running Ghidriff.
+    Plan {
+        /// Output format.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+        /// Re-evaluate every binary, not just those whose metadata changed since the last run.
+        #[arg(long)]
+        full: bool,
+    },
+    /// Print the effective config, after includes, the profile and overrides are applied.
+    ShowConfig,
+    /// Show the progress recorded for every branch and binary.
+    Status,
+    /// List the binaries tracked by each branch, expanding globs and regexes.
+    Files,
+    /// List the versions Winbindex knows about for a binary.
+    ListVersions {
+        /// Binary name, ie. `ntdll.dll`.
+        binary: String,
+        /// Only list versions from this branch.
+        #[arg(short, long)]
+        branch: Option<String>,
+        /// Only list versions for this architecture.
+        #[arg(short, long)]
+        arch: Option<String>,
+    },
+    /// Report metadata entries skipped while loading, because they are malformed or lack
+    /// `fileInfo`.
+    Anomalies {
+        /// Only check this binary, every tracked binary when omitted.
+        binary: Option<String>,
+        /// Only check this branch.
+        #[arg(short, long)]
+        branch: Option<String>,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// List the tracked binaries that changed in an update, and optionally diff them all against
+    /// their previous versions.
+    ///
+    /// The progress store is left untouched.
+    Update {
+        /// A KB (ie. `KB5034441`) or an OS build (ie. `22621.3007`, or `22621` for every revision).
+        update: String,
+        /// Only check this branch.
+        #[arg(short, long)]
+        branch: Option<String>,
+        /// Output format.
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+        /// Diff every changed binary against its previous version.
+        #[arg(long)]
+        diff: bool,
+    },
+    /// Diff two versions of a binary outside of the incremental pipeline.
+    ///
+    /// Versions may be given as a sha256, a version string (ie. `10.0.22621.3296`) or a KB
+    /// (ie. `KB5034441`). The progress store is left untouched.
+    Diff {
+        /// Branch to load the Winbindex metadata from.
+        branch: String,
+        /// Binary name, ie. `ntoskrnl.exe`.
+        binary: String,
+        /// The old version.
+        old: String,
+        /// The new version.
+        new: String,
+        /// Architecture used to disambiguate versions and KBs.
+        #[arg(short, long)]
+        arch: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl Cli {
+    /// Maps the `-v`/`-q` flags onto a log level.
+    pub const fn log_level(&self) -> log::LevelFilter {
+        if self.quiet {
+            return log::LevelFilter::Error;
+        }
+        match self.verbose {
+            0 => log::LevelFilter::Info,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! Builds a `ConfigFile` out of several layers.
+//!
+//! From lowest to highest precedence: files listed under `include:`, the config file itself,
+//! the selected entry of `profiles:` and `WINBINDEX_DIFFER_*` environment variables.
+//!
+//! Mappings are merged key by key, anything else (including lists) is replaced outright.
+//! Included files only need a `version:` if they use a different layout from the main file.
+
+use crate::config_schema::{migrate, migrate_with_warning, VERSION_KEY};
+use crate::diff_config::{ConfigFile, ConfigFileError};
+use serde_yaml::{Mapping, Value};
+use std::path::{Path, PathBuf};
+
+/// Prefix of environment variables overriding top-level keys, ie. `WINBINDEX_DIFFER_STORE_DIR`.
+pub const ENV_PREFIX: &str = "WINBINDEX_DIFFER_";
+/// Environment variable selecting a profile when none is given explicitly.
+pub const PROFILE_ENV: &str = "WINBINDEX_DIFFER_PROFILE";
+
+const INCLUDE_KEY: &str = "include";
+const PROFILES_KEY: &str = "profiles";
+
+/// Merges `over` into `base`, recursing into mappings present in both.
+fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Mapping(base), Value::Mapping(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+fn read_yaml(path: &Path) -> Result<(String, Value), ConfigFileError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| ConfigFileError::FileIOError(format!("{}: {e}", path.display())))?;
+    let value = match serde_yaml::from_str(&source) {
+        Ok(Value::Null) => Value::Mapping(Mapping::new()),
+        Ok(value @ Value::Mapping(_)) => value,
+        Ok(_) => {
+            return Err(ConfigFileError::ImproperlyFormattedConfigFile(format!(
+                "{}: expected a mapping at the top level",
+                path.display()
+            )))
+        }
+        Err(e) => {
+            return Err(ConfigFileError::ImproperlyFormattedConfigFile(format!(
+                "{}: {e}",
+                path.display()
+            )))
+        }
+    };
+    Ok((source, value))
+}
+
+/// Reads `path` with everything it includes merged underneath it. `stack` holds the files
+/// currently being read, to catch include cycles.
+fn read_layered(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Value, ConfigFileError> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| ConfigFileError::FileIOError(format!("{}: {e}", path.display())))?;
+    if stack.contains(&canonical) {
+        return Err(ConfigFileError::IncludeCycle(path.display().to_string()));
+    }
+    let (_source, mut value) = read_yaml(path)?;
+    // Included fragments without `version:` follow the layout of the file including them.
+    if stack.is_empty() || value.get(VERSION_KEY).is_some() {
+        migrate_with_warning(&mut value, &path.display().to_string())?;
+    }
+    let includes = match value.as_mapping_mut().and_then(|m| m.remove(INCLUDE_KEY)) {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Sequence(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => Err(ConfigFileError::ImproperlyFormattedConfigFile(format!(
+                    "{}: include: entries must be paths",
+                    path.display()
+                ))),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(ConfigFileError::ImproperlyFormattedConfigFile(format!(
+                "{}: include: must be a path or a list of paths",
+                path.display()
+            )))
+        }
+    };
+
+    stack.push(canonical);
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = Value::Mapping(Mapping::new());
+    for include in includes {
+        merge(&mut merged, read_layered(&parent.join(include), stack)?);
+    }
+    stack.pop();
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Applies the profile named `profile`, dropping the `profiles` key either way.
+fn apply_profile(value: &mut Value, profile: Option<&str>) -> Result<(), ConfigFileError> {
+    let profiles = value.as_mapping_mut().and_then(|m| m.remove(PROFILES_KEY));
+    let Some(profile) = profile else {
+        return Ok(());
+    };
+    let selected = profiles
+        .as_ref()
+        .and_then(|profiles| profiles.get(profile))
+        .cloned()
+        .ok_or_else(|| ConfigFileError::UnknownProfile(profile.to_string()))?;
+    merge(value, selected);
+    // A profile may set `version:` too, which has to be checked like any other.
+    migrate(value, &format!("profile {profile}"))?;
+    Ok(())
+}
+
+/// How the value of an environment override is read.
+#[derive(Clone, Copy)]
+enum EnvValue {
+    /// Taken verbatim, so paths like `2024` or `yes` stay strings.
+    Text,
+    /// Parsed as YAML, ie. `true`, `2` or `{sxs: true}`.
+    Yaml,
+}
+
+/// Top-level keys that can be overridden from the environment. `version` describes the layout of
+/// the file itself, so it can't be.
+const ENV_KEYS: [(&str, EnvValue); 6] = [
+    ("branches", EnvValue::Yaml),
+    ("store_dir", EnvValue::Text),
+    ("repo_dir", EnvValue::Text),
+    ("ghidriff", EnvValue::Yaml),
+    ("offline", EnvValue::Yaml),
+    ("max_repo_age", EnvValue::Text),
+];
+
+/// Applies `WINBINDEX_DIFFER_<KEY>` overrides for the keys in [`ENV_KEYS`]. Mappings are merged
+/// in, ie. `WINBINDEX_DIFFER_GHIDRIFF='{sxs: true}'`. Other variables with the prefix are ignored
+/// with a warning, as they may well be meant for something else, ie. an `auth.token_env`.
+fn apply_env(value: &mut Value, env: impl IntoIterator<Item = (String, String)>) {
+    let Some(mapping) = value.as_mapping_mut() else {
+        return;
+    };
+    for (name, raw) in env {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        if name == PROFILE_ENV {
+            continue;
+        }
+        let key = key.to_lowercase();
+        let Some((_, kind)) = ENV_KEYS.iter().find(|(known, _)| *known == key) else {
+            log::warn!("Ignoring {name}, {key:?} is not a top-level config key");
+            continue;
+        };
+        let over = match kind {
+            EnvValue::Text => Value::String(raw),
+            EnvValue::Yaml => serde_yaml::from_str(&raw).unwrap_or(Value::String(raw)),
+        };
+        log::debug!("{name} overrides {key}");
+        let key = Value::String(key);
+        match mapping.get_mut(&key) {
+            Some(existing) => merge(existing, over),
+            None => {
+                mapping.insert(key, over);
+            }
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Loads a config file along with its includes, the given profile (or the one named by
+    /// `WINBINDEX_DIFFER_PROFILE`) and environment overrides.
+    ///
+    /// # Errors
+    /// Returns `NotFound` if `path` does not exist. Fails if any layer can't be read, parsed or
+    /// migrated, includes form a cycle, the profile does not exist, or the merged config is not
+    /// a valid `ConfigFile`.
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Self, ConfigFileError> {
+        Self::load_with_env(path, profile, std::env::vars())
+    }
+
+    /// As [`Self::load`], taking the environment from `env`.
+    ///
+    /// # Errors
+    /// See [`Self::load`].
+    pub fn load_with_env(
+        path: &Path,
+        profile: Option<&str>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigFileError> {
+        if !path.exists() {
+            return Err(ConfigFileError::NotFound(path.display().to_string()));
+        }
+        let env: Vec<(String, String)> = env.into_iter().collect();
+        let profile = profile.map(str::to_string).or_else(|| {
+            env.iter()
+                .find(|(name, _)| name == PROFILE_ENV)
+                .map(|(_, value)| value.clone())
+        });
+        let (source, own) = read_yaml(path)?;
+        let mut merged = read_layered(path, &mut Vec::new())?;
+        apply_profile(&mut merged, profile.as_deref())?;
+        apply_env(&mut merged, env);
+
+        let origin = path.display().to_string();
+        if merged == own {
+            // Nothing was layered or migrated, parse the text so errors keep their line numbers.
+            return Self::parse(&source, &origin);
+        }
+        let config: Self = serde_yaml::from_value(merged).map_err(|e| {
+            ConfigFileError::ImproperlyFormattedConfigFile(format!(
+                "{origin} (with includes, profile and environment applied): {e}"
+            ))
+        })?;
+        Ok(Self { source, ..config })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn yaml(source: &str) -> Value {
+        serde_yaml::from_str(source).unwrap()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| ((*k).to_string(), (*v).to_string())).collect()
+    }
+
+    #[test]
+    fn merge_recurses_into_mappings() {
+        let mut base = yaml("a: {b: 1, c: 2}\nd: 3");
+        merge(&mut base, yaml("a: {c: 4, e: 5}\nf: 6"));
+        assert_eq!(base, yaml("a: {b: 1, c: 4, e: 5}\nd: 3\nf: 6"));
+    }
+
+    #[test]
+    fn merge_replaces_lists_and_scalars() {
+        let mut base = yaml("files: [a, b]\nstore_dir: x");
+        merge(&mut base, yaml("files: [c]\nstore_dir: {nested: y}"));
+        assert_eq!(base, yaml("files: [c]\nstore_dir: {nested: y}"));
+    }
+
+    #[test]
+    fn apply_profile_merges_selected_profile() {
+        let mut value =
+            yaml("version: 2\nstore_dir: s\nghidriff: {sxs: false}\nprofiles: {ci: {ghidriff: {sxs: true}}}");
+        apply_profile(&mut value, Some("ci")).unwrap();
+        assert_eq!(value, yaml("version: 2\nstore_dir: s\nghidriff: {sxs: true}"));
+    }
+
+    #[test]
+    fn apply_profile_refuses_future_versions() {
+        let mut value = yaml("version: 2\nprofiles: {ci: {version: 7}}");
+        assert!(matches!(
+            apply_profile(&mut value, Some("ci")),
+            Err(ConfigFileError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn apply_profile_drops_profiles_without_selection() {
+        let mut value = yaml("store_dir: s\nprofiles: {ci: {store_dir: t}}");
+        apply_profile(&mut value, None).unwrap();
+        assert_eq!(value, yaml("store_dir: s"));
+    }
+
+    #[test]
+    fn apply_profile_rejects_unknown_profile() {
+        let mut value = yaml("profiles: {ci: {store_dir: t}}");
+        assert!(matches!(
+            apply_profile(&mut value, Some("nightly")),
+            Err(ConfigFileError::UnknownProfile(name)) if name == "nightly"
+        ));
+    }
+
+    /// Writes `files` into a fresh directory, removed when the returned guard is dropped.
+    fn config_dir(test: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new(test);
+        for (name, contents) in files {
+            std::fs::write(dir.path().join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    fn read(dir: &TempDir, name: &str) -> Result<Value, ConfigFileError> {
+        read_layered(&dir.path().join(name), &mut Vec::new())
+    }
+
+    #[test]
+    fn unversioned_includes_follow_the_including_file() {
+        let dir = config_dir(
+            "unversioned_include",
+            &[
+                ("config.yaml", "version: 2\ninclude: branches.yaml\nstore_dir: s"),
+                ("branches.yaml", "branches: {}\nstore_dir: t\nrepo_dir: r"),
+            ],
+        );
+        assert_eq!(
+            read(&dir, "config.yaml").unwrap(),
+            yaml("branches: {}\nstore_dir: s\nrepo_dir: r\nversion: 2")
+        );
+    }
+
+    #[test]
+    fn versioned_includes_are_checked() {
+        let dir = config_dir(
+            "future_include",
+            &[
+                ("config.yaml", "version: 2\ninclude: branches.yaml"),
+                ("branches.yaml", "version: 3\nbranches: {}"),
+            ],
+        );
+        assert!(matches!(read(&dir, "config.yaml"), Err(ConfigFileError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn include_cycles_are_rejected() {
+        let dir = config_dir(
+            "include_cycle",
+            &[("a.yaml", "include: b.yaml"), ("b.yaml", "include: a.yaml")],
+        );
+        assert!(matches!(read(&dir, "a.yaml"), Err(ConfigFileError::IncludeCycle(_))));
+    }
+
+    #[test]
+    fn config_dirs_are_removed() {
+        let dir = config_dir("removed", &[("a.yaml", "store_dir: s")]);
+        let path = dir.path().to_path_buf();
+        assert!(path.join("a.yaml").exists());
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn apply_env_types_values_by_key() {
+        let mut value = yaml("store_dir: s\nghidriff: {base_address: 0}");
+        apply_env(
+            &mut value,
+            env(&[
+                ("WINBINDEX_DIFFER_OFFLINE", "true"),
+                ("WINBINDEX_DIFFER_STORE_DIR", "2024"),
+                ("WINBINDEX_DIFFER_GHIDRIFF", "{sxs: true}"),
+            ]),
+        );
+        assert_eq!(
+            value,
+            yaml("store_dir: '2024'\nghidriff: {base_address: 0, sxs: true}\noffline: true")
+        );
+    }
+
+    #[test]
+    fn apply_env_ignores_unknown_keys() {
+        let mut value = yaml("store_dir: s");
+        apply_env(
+            &mut value,
+            env(&[
+                ("WINBINDEX_DIFFER_TOKEN", "secret"),
+                ("WINBINDEX_DIFFER_VERSION", "99"),
+                ("WINBINDEX_DIFFER_PROFILE", "ci"),
+                ("WINBINDEX_DIFFER_", "x"),
+                ("OTHER_OFFLINE", "true"),
+            ]),
+        );
+        assert_eq!(value, yaml("store_dir: s"));
+    }
+}
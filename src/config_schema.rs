@@ -0,0 +1,218 @@
+//! Versioning of the config file layout.
+//!
+//! Every config file carries a top-level `version:`, except for included fragments, which follow
+//! the layout of the file including them. Files written for an older layout are
+//! upgraded in memory one version at a time when loaded, and `migrate` rewrites them on disk.
+//! Files from a newer version than this build understands are refused rather than guessed at.
+
+use crate::diff_config::{ConfigFile, ConfigFileError};
+use serde_yaml::{Mapping, Value};
+use std::path::{Path, PathBuf};
+
+/// The layout version written by `init` and expected by `ConfigFile`.
+pub const CURRENT_VERSION: u64 = 2;
+/// Version assumed for files without a `version:` key.
+const UNVERSIONED: u64 = 1;
+pub(crate) const VERSION_KEY: &str = "version";
+
+/// Upgrades a config mapping by a single version.
+type Migration = fn(&mut Mapping) -> Result<(), String>;
+
+/// Migrations keyed by the version they upgrade from.
+const MIGRATIONS: &[(u64, Migration)] = &[(1, v1_to_v2)];
+
+/// Version 1 is the original, unversioned layout with `branches`, `store_dir` and `repo_dir` at
+/// the top level and plain binary names under `files`. All of it is still valid in version 2,
+/// which only adds keys, so there is nothing to rewrite.
+#[allow(clippy::unnecessary_wraps)] // Must match `Migration`.
+const fn v1_to_v2(_config: &mut Mapping) -> Result<(), String> {
+    Ok(())
+}
+
+const TEMPLATE: &str = r#"version: 2
+# Diffs, downloaded binaries and progress are written here.
+store_dir: ./store
+# Winbindex repositories are cloned here.
+repo_dir: ./repos
+branches:
+    main:
+        repo_url: https://github.com/m417z/winbindex.git
+        branch: gh-pages
+        data_dir: data/by_filename_compressed
+        files: ["ntdll.dll"]
+"#;
+
+/// Reads the layout version of a config, treating a missing `version:` as the unversioned layout.
+///
+/// # Errors
+/// Returns `UnsupportedVersion` if `version:` is not a positive integer.
+pub fn schema_version(config: &Value, origin: &str) -> Result<u64, ConfigFileError> {
+    config.get(VERSION_KEY).map_or(Ok(UNVERSIONED), |version| {
+        version.as_u64().filter(|v| *v > 0).ok_or_else(|| {
+            ConfigFileError::UnsupportedVersion(format!(
+                "{origin}: version must be a positive integer, found {}",
+                serde_yaml::to_string(version).unwrap_or_default().trim()
+            ))
+        })
+    })
+}
+
+/// Upgrades `config` to `CURRENT_VERSION` in place, returning the version it started at.
+///
+/// # Errors
+/// Returns `UnsupportedVersion` for versions newer than `CURRENT_VERSION`, and
+/// `ImproperlyFormattedConfigFile` if `config` is not a mapping or a migration step fails.
+pub fn migrate(config: &mut Value, origin: &str) -> Result<u64, ConfigFileError> {
+    let from = schema_version(config, origin)?;
+    if from > CURRENT_VERSION {
+        return Err(ConfigFileError::UnsupportedVersion(format!(
+            "{origin}: version {from} is newer than the supported version {CURRENT_VERSION}, upgrade winbindex_differ"
+        )));
+    }
+    let Some(mapping) = config.as_mapping_mut() else {
+        return Err(ConfigFileError::ImproperlyFormattedConfigFile(format!(
+            "{origin}: expected a mapping at the top level"
+        )));
+    };
+    for version in from..CURRENT_VERSION {
+        let step = MIGRATIONS
+            .iter()
+            .find(|(step_from, _)| *step_from == version)
+            .map(|(_, step)| step)
+            .ok_or_else(|| {
+                ConfigFileError::UnsupportedVersion(format!("{origin}: no migration from version {version}"))
+            })?;
+        step(mapping).map_err(|e| {
+            ConfigFileError::ImproperlyFormattedConfigFile(format!(
+                "{origin}: migrating from version {version}: {e}"
+            ))
+        })?;
+    }
+    if from < CURRENT_VERSION {
+        // Keep `version:` at the top, where `init` puts it.
+        mapping.remove(VERSION_KEY);
+        let rest = std::mem::take(mapping);
+        mapping.insert(Value::from(VERSION_KEY), Value::from(CURRENT_VERSION));
+        mapping.extend(rest);
+    }
+    Ok(from)
+}
+
+/// As [`migrate`], warning when `config` uses an older layout.
+pub(crate) fn migrate_with_warning(config: &mut Value, origin: &str) -> Result<u64, ConfigFileError> {
+    let from = migrate(config, origin)?;
+    if from < CURRENT_VERSION {
+        log::warn!("{origin} uses config version {from}, run `migrate` to upgrade it to {CURRENT_VERSION}");
+    }
+    Ok(from)
+}
+
+impl ConfigFile {
+    /// Writes a starter config to `path`.
+    ///
+    /// # Errors
+    /// Returns `AlreadyExists` rather than overwriting an existing file, or `FileIOError` if the
+    /// file can't be written.
+    pub fn init(path: &Path) -> Result<(), ConfigFileError> {
+        if path.exists() {
+            return Err(ConfigFileError::AlreadyExists(path.display().to_string()));
+        }
+        std::fs::write(path, TEMPLATE)
+            .map_err(|e| ConfigFileError::FileIOError(format!("{}: {e}", path.display())))
+    }
+
+    /// Rewrites the config file at `path` in the current layout, keeping the original next to it
+    /// as `<path>.v<version>.bak`. Comments are not preserved. Returns the backup path, or `None`
+    /// if the file was already current.
+    ///
+    /// # Errors
+    /// Fails if the file can't be read, parsed, migrated or written.
+    pub fn migrate_file(path: &Path) -> Result<Option<PathBuf>, ConfigFileError> {
+        let io_error = |e: std::io::Error| ConfigFileError::FileIOError(format!("{}: {e}", path.display()));
+        let origin = path.display().to_string();
+        let source = std::fs::read_to_string(path).map_err(io_error)?;
+        let mut config: Value = serde_yaml::from_str(&source)
+            .map_err(|e| ConfigFileError::ImproperlyFormattedConfigFile(format!("{origin}: {e}")))?;
+        let from = migrate(&mut config, &origin)?;
+        if from == CURRENT_VERSION {
+            return Ok(None);
+        }
+        let migrated = serde_yaml::to_string(&config)
+            .map_err(|e| ConfigFileError::ImproperlyFormattedConfigFile(format!("{origin}: {e}")))?;
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".v{from}.bak"));
+        let backup = PathBuf::from(backup);
+        std::fs::copy(path, &backup).map_err(io_error)?;
+        std::fs::write(path, migrated).map_err(io_error)?;
+        Ok(Some(backup))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(source: &str) -> Value {
+        serde_yaml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn migrate_upgrades_unversioned_layout() {
+        let mut config = yaml("store_dir: s\nrepo_dir: r\nbranches: {}");
+        assert_eq!(migrate(&mut config, "test").unwrap(), UNVERSIONED);
+        assert_eq!(config, yaml("version: 2\nstore_dir: s\nrepo_dir: r\nbranches: {}"));
+        let keys: Vec<_> = config.as_mapping().unwrap().keys().collect();
+        assert_eq!(keys[0], &Value::from(VERSION_KEY));
+    }
+
+    #[test]
+    fn migrate_upgrades_explicit_old_version() {
+        let mut config = yaml("store_dir: s\nversion: 1");
+        assert_eq!(migrate(&mut config, "test").unwrap(), 1);
+        assert_eq!(config.get(VERSION_KEY), Some(&Value::from(CURRENT_VERSION)));
+    }
+
+    #[test]
+    fn migrate_leaves_current_version_alone() {
+        let mut config = yaml("store_dir: s\nversion: 2");
+        let original = config.clone();
+        assert_eq!(migrate(&mut config, "test").unwrap(), CURRENT_VERSION);
+        assert_eq!(config, original);
+    }
+
+    #[test]
+    fn migrate_refuses_future_versions() {
+        let mut config = yaml("version: 3");
+        assert!(matches!(
+            migrate(&mut config, "test"),
+            Err(ConfigFileError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn migrate_rejects_invalid_versions() {
+        for version in ["version: 0", "version: two", "version: -1", "version: 1.5"] {
+            assert!(
+                matches!(
+                    migrate(&mut yaml(version), "test"),
+                    Err(ConfigFileError::UnsupportedVersion(_))
+                ),
+                "{version} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn migrate_rejects_non_mappings() {
+        assert!(matches!(
+            migrate(&mut yaml("[a, b]"), "test"),
+            Err(ConfigFileError::ImproperlyFormattedConfigFile(_))
+        ));
+    }
+
+    #[test]
+    fn template_is_current() {
+        let config = ConfigFile::parse(TEMPLATE, "template").unwrap();
+        assert_eq!(config.version, CURRENT_VERSION);
+    }
+}
@@ -0,0 +1,287 @@
+//! Sanity checks for a parsed `ConfigFile`, reporting problems against the offending key.
+//! Syntax errors and unknown keys are caught while parsing; this covers everything serde can't.
+
+use crate::diff_config::{list_data_dir, list_data_tree, BranchConfig, ConfigFile, FileEntry};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ValidationIssue {
+    pub severity: Severity,
+    /// Dotted path to the offending key, ie. `branches.main.repo_url`.
+    pub key: String,
+    /// Best effort line number of the key within the config file.
+    pub line: Option<usize>,
+    pub message: String,
+}
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.line {
+            Some(line) => write!(f, "{severity}: {} (line {line}): {}", self.key, self.message),
+            None => write!(f, "{severity}: {}: {}", self.key, self.message),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+}
+impl ValidationReport {
+    #[must_use]
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|i| i.severity == Severity::Error)
+    }
+    #[must_use]
+    pub fn errors(&self) -> Vec<ValidationIssue> {
+        self.issues
+            .iter()
+            .filter(|i| i.severity == Severity::Error)
+            .cloned()
+            .collect()
+    }
+    pub fn warnings(&self) -> impl Iterator<Item = &ValidationIssue> {
+        self.issues.iter().filter(|i| i.severity == Severity::Warning)
+    }
+}
+
+/// Finds the first line at or after `from` containing `needle`, returning a 1-based line number.
+/// When `within` is set the search stops once indentation drops back to that of line `within`.
+fn find_line(source: &str, from: usize, within: Option<usize>, needle: &str) -> Option<usize> {
+    let lines: Vec<&str> = source.lines().collect();
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let floor = within.and_then(|l| lines.get(l - 1)).map(|l| indent(l));
+    for (idx, line) in lines.iter().enumerate().skip(from.saturating_sub(1)) {
+        if let Some(floor) = floor {
+            if idx + 1 > from && !line.trim().is_empty() && indent(line) <= floor {
+                return None;
+            }
+        }
+        if line.contains(needle) {
+            return Some(idx + 1);
+        }
+    }
+    None
+}
+
+/// Checks that `url` looks like something git can clone from.
+fn is_valid_repo_url(url: &str) -> bool {
+    if let Ok(parsed) = reqwest::Url::parse(url) {
+        return match parsed.scheme() {
+            "http" | "https" | "git" | "ssh" => parsed.host_str().is_some(),
+            "file" => true,
+            _ => false,
+        };
+    }
+    // scp-like syntax, ie. `git@github.com:m417z/winbindex.git`
+    if let Some((host, path)) = url.split_once(':') {
+        return !host.is_empty() && !path.is_empty() && !host.contains('/');
+    }
+    Path::new(url).exists()
+}
+
+/// Locates keys and values of one branch within the config source.
+struct BranchSource<'a> {
+    source: &'a str,
+    prefix: String,
+    line: Option<usize>,
+}
+impl BranchSource<'_> {
+    fn key_line(&self, key: &str) -> Option<usize> {
+        self.line
+            .and_then(|l| find_line(self.source, l, Some(l), &format!("{key}:")))
+    }
+    fn value_line(&self, key: &str, value: &str) -> Option<usize> {
+        self.key_line(key)
+            .and_then(|l| find_line(self.source, l, self.line, value))
+    }
+    fn key(&self, key: &str) -> String {
+        format!("{}.{key}", self.prefix)
+    }
+}
+
+impl ValidationReport {
+    fn push(&mut self, severity: Severity, key: String, line: Option<usize>, message: String) {
+        self.issues.push(ValidationIssue { severity, key, line, message });
+    }
+}
+
+impl ConfigFile {
+    /// Validates the config, checking the cloned repositories where they exist.
+    #[must_use]
+    pub fn validate(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        let source = self.source.as_str();
+
+        for (key, value) in [("store_dir", &self.store_dir), ("repo_dir", &self.repo_dir)] {
+            if value.trim().is_empty() {
+                let line = find_line(source, 1, None, &format!("{key}:"));
+                report.push(Severity::Error, key.to_string(), line, "must not be empty".to_string());
+            }
+        }
+        let branches_line = find_line(source, 1, None, "branches:");
+        if self.branches.is_empty() {
+            report.push(Severity::Warning, "branches".to_string(), branches_line, "no branches are configured".to_string());
+        }
+
+        let mut branch_names: Vec<&String> = self.branches.keys().collect();
+        branch_names.sort();
+        for branch_name in branch_names {
+            let branch_source = BranchSource {
+                source,
+                prefix: format!("branches.{branch_name}"),
+                line: find_line(source, branches_line.unwrap_or(1), None, &format!("{branch_name}:")),
+            };
+            self.validate_branch(&mut report, branch_name, &branch_source);
+            self.validate_checkout(&mut report, branch_name, &branch_source);
+        }
+        report
+    }
+
+    /// Checks everything that can be checked without a local clone.
+    fn validate_branch(&self, report: &mut ValidationReport, branch_name: &str, at: &BranchSource) {
+        let branch = &self.branches[branch_name];
+        if !is_valid_repo_url(&branch.repo_url) {
+            report.push(
+                Severity::Error,
+                at.key("repo_url"),
+                at.key_line("repo_url"),
+                format!("{:?} is not a valid git URL", branch.repo_url),
+            );
+        }
+        if branch.branch.trim().is_empty() {
+            report.push(Severity::Error, at.key("branch"), at.key_line("branch"), "must not be empty".to_string());
+        }
+        Self::validate_access(report, branch, at);
+        if branch.depth == Some(0) {
+            report.push(Severity::Error, at.key("depth"), at.key_line("depth"), "must be at least 1, or omitted for full history".to_string());
+        }
+        if branch.at.is_some() && branch.depth.is_some() {
+            report.push(Severity::Warning, at.key("at"), at.key_line("at"), "a shallow clone may not reach back to this snapshot".to_string());
+        }
+        if branch.files.is_empty() {
+            report.push(Severity::Warning, at.key("files"), at.key_line("files"), "no files are tracked".to_string());
+        }
+        for file in &branch.files {
+            let config = file.config();
+            let key = at.key(&format!("files.{}", file.label()));
+            let line = at.value_line("files", file.label());
+            let selectors = [!config.name.is_empty(), config.glob.is_some(), config.regex.is_some()];
+            if selectors.iter().filter(|set| **set).count() != 1 {
+                report.push(Severity::Error, key.clone(), line, "exactly one of name, glob and regex must be set".to_string());
+            }
+            if let Err(e) = config.pattern() {
+                report.push(Severity::Error, key.clone(), line, e.to_string());
+            }
+            if let (Some(min), Some(max)) = (&config.min_version, &config.max_version) {
+                if min > max {
+                    report.push(Severity::Error, key, line, format!("min_version {min} is greater than max_version {max}"));
+                }
+            }
+        }
+        for exclude in &branch.exclude {
+            if let Err(e) = glob::Pattern::new(exclude) {
+                report.push(Severity::Error, at.key("exclude"), at.value_line("exclude", exclude), format!("glob {exclude:?}: {e}"));
+            }
+        }
+    }
+
+    /// Checks the credentials and proxy of a branch.
+    fn validate_access(report: &mut ValidationReport, branch: &BranchConfig, at: &BranchSource) {
+        if let Some(key) = branch.auth.ssh_key_path() {
+            if !key.is_file() {
+                report.push(Severity::Error, at.key("auth.ssh_key"), at.key_line("ssh_key"), format!("{} does not exist", key.display()));
+            }
+        }
+        for (key, env) in [
+            ("ssh_key_passphrase_env", &branch.auth.ssh_key_passphrase_env),
+            ("token_env", &branch.auth.token_env),
+        ] {
+            if let Some(env) = env.as_deref().filter(|env| std::env::var_os(env).is_none()) {
+                report.push(Severity::Warning, at.key(&format!("auth.{key}")), at.key_line(key), format!("environment variable {env} is not set"));
+            }
+        }
+        if let Some(proxy) = branch.proxy.as_deref().filter(|p| *p != "auto") {
+            let valid = reqwest::Url::parse(proxy).is_ok_and(|url| {
+                matches!(url.scheme(), "http" | "https" | "socks5") && url.host_str().is_some()
+            });
+            if !valid {
+                report.push(Severity::Error, at.key("proxy"), at.key_line("proxy"), format!("{proxy:?} is not a proxy URL or `auto`"));
+            }
+        }
+    }
+
+    /// Checks `data_dir` and `files` against the local clone, if there is one.
+    fn validate_checkout(&self, report: &mut ValidationReport, branch_name: &str, at: &BranchSource) {
+        let branch = &self.branches[branch_name];
+        let repo_path = Path::new(&self.repo_dir).join(branch_name);
+        if !self.is_cloned(branch_name) {
+            report.push(
+                Severity::Warning,
+                at.prefix.clone(),
+                at.line,
+                format!("{} has not been cloned yet, run `sync` to check data_dir and files", repo_path.display()),
+            );
+            return;
+        }
+        let data_path = repo_path.join(&branch.data_dir);
+        let commit = match self.snapshot_commit(branch_name) {
+            Ok(commit) => commit,
+            Err(e) => {
+                report.push(Severity::Error, at.key("at"), at.key_line("at"), format!("{e}"));
+                return;
+            }
+        };
+        let available = if branch.lists_from_tree() {
+            list_data_tree(&repo_path, &branch.git_data_dir(), commit)
+        } else {
+            list_data_dir(&data_path)
+        };
+        let Ok(available) = available else {
+            report.push(
+                Severity::Error,
+                at.key("data_dir"),
+                at.key_line("data_dir"),
+                format!("{} does not exist in the cloned repository", data_path.display()),
+            );
+            return;
+        };
+        if let Ok(resolved) = branch.resolve_files_from(|| Ok(available.clone())) {
+            for pattern in resolved.patterns.iter().filter(|p| p.matched == 0) {
+                report.push(
+                    Severity::Warning,
+                    at.key(&format!("files.{}", pattern.pattern)),
+                    at.value_line("files", &pattern.pattern),
+                    format!("matched no binaries in {}", data_path.display()),
+                );
+            }
+        }
+        let names = branch
+            .files
+            .iter()
+            .map(FileEntry::config)
+            .filter(|c| c.glob.is_none() && c.regex.is_none());
+        for file in names {
+            let name = file.name.as_str();
+            if !available.iter().any(|a| a == name) {
+                report.push(
+                    Severity::Warning,
+                    at.key("files"),
+                    at.value_line("files", name),
+                    format!("{name} has no {name}.json.gz in {}", data_path.display()),
+                );
+            }
+        }
+    }
+}
@@ -1,76 +1,747 @@
 //! Data structures for handling diffing configuration files
 //! See `../sample_config.yaml` for an example
 //! 
-use crate::git_utils::{GitError, GitHelper};
-use serde::{Deserialize, Serialize};
+use crate::config_schema::{migrate_with_warning, CURRENT_VERSION};
+use crate::config_validation::ValidationIssue;
+use crate::ghidriff_utils::GhidriffOptions;
+use crate::git_utils::{
+    last_synced, list_commit_dir, list_head_dir, resolve_snapshot, GitAuth, GitCause, GitError, GitHelper,
+    RewritePolicy, Snapshot,
+};
+use crate::winbindex_utils::{Arch, BinaryVersion, WinbindexEntry, WinbindexFileData};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum ConfigFileError {
-    FileIOError(),
-    ImproperlyFormattedConfigFile,
-    ConfigFileCreation,
+    FileIOError(String),
+    ImproperlyFormattedConfigFile(String),
+    NotFound(String),
+    AlreadyExists(String),
+    UnsupportedVersion(String),
+    NotCloned(String),
     GitError(GitError),
+    Invalid(Vec<ValidationIssue>),
+    InvalidPattern(String),
+    IncludeCycle(String),
+    UnknownProfile(String),
+}
+impl std::fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FileIOError(e)
+            | Self::ImproperlyFormattedConfigFile(e)
+            | Self::InvalidPattern(e)
+            | Self::UnsupportedVersion(e) => write!(f, "{e}"),
+            Self::NotFound(path) => write!(f, "{path} does not exist, run `init` to create it"),
+            Self::AlreadyExists(path) => write!(f, "{path} already exists"),
+            Self::NotCloned(branch) => {
+                write!(f, "branch {branch} has not been cloned, sync it without running offline first")
+            }
+            Self::IncludeCycle(path) => write!(f, "{path} includes itself"),
+            Self::UnknownProfile(name) => write!(f, "profile {name} is not defined under profiles:"),
+            Self::GitError(e) => write!(f, "{e}"),
+            Self::Invalid(issues) => {
+                write!(f, "{} problem(s) found", issues.len())?;
+                for issue in issues {
+                    write!(f, "\n    {issue}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Where a branch's Winbindex metadata is read from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataSource {
+    /// Files checked out in the working tree.
+    #[default]
+    WorkTree,
+    /// Blobs in the git object database at `HEAD`. New clones are bare.
+    Git,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct BranchConfig {
     pub repo_url: String,
     pub branch: String,
     pub data_dir:String,
-    pub files: Vec<String>,
+    /// Only clone and fetch this many commits of history, full history when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+    /// Only check out the metadata of the tracked binaries instead of the whole tree.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sparse: bool,
+    /// Where metadata is read from, the working tree unless set.
+    #[serde(default, skip_serializing_if = "MetadataSource::is_default")]
+    pub metadata_source: MetadataSource,
+    /// What to do when the remote branch was rewritten, `fail` unless set.
+    #[serde(default, skip_serializing_if = "RewritePolicy::is_default")]
+    pub on_rewrite: RewritePolicy,
+    /// Credentials for private mirrors.
+    #[serde(default, skip_serializing_if = "GitAuth::is_empty")]
+    pub auth: GitAuth,
+    /// Proxy URL for fetches, or `auto` to use the proxy configured in git.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Move a broken clone aside and clone it again on sync, rather than failing.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub self_heal: bool,
+    /// Read metadata as of this commit or date rather than the tip of the branch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<Snapshot>,
+    pub files: Vec<FileEntry>,
+    /// Globs removing binaries matched by `glob` and `regex` file entries.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Ghidriff options for every file of this branch.
+    #[serde(default, skip_serializing_if = "GhidriffOptions::is_empty")]
+    pub ghidriff: GhidriffOptions,
+}
+
+/// Settings for a single tracked binary, or for every binary matching a pattern.
+/// Exactly one of `name`, `glob` and `regex` should be set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// Glob matched against the binaries in `data_dir`, ie. `*.sys`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glob: Option<String>,
+    /// Regex matched against the binaries in `data_dir`, ie. `^win32k.*`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    /// Architectures to diff, all of them when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arches: Vec<Arch>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_version: Option<BinaryVersion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_version: Option<BinaryVersion>,
+    #[serde(default, skip_serializing_if = "GhidriffOptions::is_empty")]
+    pub ghidriff: GhidriffOptions,
+}
+/// A compiled `glob` or `regex` from a `FileConfig`.
+pub enum FilePattern {
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+}
+impl FilePattern {
+    #[must_use]
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(pattern) => pattern.matches(name),
+            Self::Regex(pattern) => pattern.is_match(name),
+        }
+    }
+}
+
+/// How many binaries a file pattern expanded to.
+#[derive(Serialize, Debug, Clone)]
+pub struct PatternMatches {
+    pub pattern: String,
+    pub matched: usize,
+}
+
+/// The concrete binaries tracked by a branch once patterns have been expanded.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ResolvedFiles {
+    pub files: Vec<FileConfig>,
+    pub patterns: Vec<PatternMatches>,
+}
+
+impl FileConfig {
+    /// The binary name, or the pattern for pattern entries.
+    #[must_use]
+    pub fn label(&self) -> &str {
+        self.glob
+            .as_deref()
+            .or(self.regex.as_deref())
+            .unwrap_or(&self.name)
+    }
+    /// Compiles the `glob` or `regex` of this entry, if it has one.
+    ///
+    /// # Errors
+    /// Returns `InvalidPattern` if the pattern does not compile.
+    pub fn pattern(&self) -> Result<Option<FilePattern>, ConfigFileError> {
+        if let Some(glob) = &self.glob {
+            let pattern = glob::Pattern::new(glob)
+                .map_err(|e| ConfigFileError::InvalidPattern(format!("glob {glob:?}: {e}")))?;
+            return Ok(Some(FilePattern::Glob(pattern)));
+        }
+        if let Some(regex) = &self.regex {
+            let pattern = regex::Regex::new(regex)
+                .map_err(|e| ConfigFileError::InvalidPattern(format!("regex {regex:?}: {e}")))?;
+            return Ok(Some(FilePattern::Regex(pattern)));
+        }
+        Ok(None)
+    }
+    /// Checks whether an entry passes the architecture and version filters.
+    #[must_use]
+    pub fn accepts(&self, entry: &WinbindexEntry) -> bool {
+        let version = entry.get_version();
+        (self.arches.is_empty() || entry.get_arch().is_some_and(|a| self.arches.contains(&a)))
+            && self.min_version.as_ref().is_none_or(|min| &version >= min)
+            && self.max_version.as_ref().is_none_or(|max| &version <= max)
+    }
+    /// A hash of the architecture and version filters, the same across runs and builds, so a
+    /// change to them can be detected from the progress store. The order of `arches` is ignored.
+    #[must_use]
+    pub fn selector_hash(&self) -> String {
+        let mut arches: Vec<String> = self.arches.iter().map(|arch| String::from(*arch)).collect();
+        arches.sort();
+        arches.dedup();
+        let version = |v: Option<&BinaryVersion>| v.map(ToString::to_string).unwrap_or_default();
+        let selector = format!(
+            "arches={};min_version={};max_version={}",
+            arches.join(","),
+            version(self.min_version.as_ref()),
+            version(self.max_version.as_ref())
+        );
+        // FNV-1a, as `DefaultHasher` may change between Rust releases.
+        let hash = selector.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        format!("{hash:016x}")
+    }
+    /// Returns the subset of `file_data` that passes the architecture and version filters.
+    #[must_use]
+    pub fn filter(&self, file_data: &WinbindexFileData) -> WinbindexFileData {
+        WinbindexFileData {
+            anomalies: file_data.anomalies.clone(),
+            ..WinbindexFileData::new(
+                file_data
+                    .data
+                    .iter()
+                    .filter(|(_k, v)| self.accepts(v))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// An entry in `BranchConfig.files`, either a bare binary name or a `FileConfig`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum FileEntry {
+    Name(String),
+    Detailed(FileConfig),
+}
+impl FileEntry {
+    /// The binary name, or the pattern for pattern entries.
+    #[must_use]
+    pub fn label(&self) -> &str {
+        match self {
+            Self::Name(name) => name,
+            Self::Detailed(config) => config.label(),
+        }
+    }
+    /// The settings for this file, with defaults filled in for the bare name form.
+    /// Bare names containing glob metacharacters are treated as globs.
+    #[must_use]
+    pub fn config(&self) -> FileConfig {
+        match self {
+            Self::Name(name) if name.contains(['*', '?', '[']) => FileConfig {
+                glob: Some(name.clone()),
+                ..FileConfig::default()
+            },
+            Self::Name(name) => FileConfig {
+                name: name.clone(),
+                ..FileConfig::default()
+            },
+            Self::Detailed(config) => config.clone(),
+        }
+    }
+}
+// Hand written rather than `#[serde(untagged)]` so mistakes in the object form keep their
+// error message and location.
+impl<'de> Deserialize<'de> for FileEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FileEntryVisitor;
+        impl<'de> serde::de::Visitor<'de> for FileEntryVisitor {
+            type Value = FileEntry;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a binary name or a map with a `name` key")
+            }
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<FileEntry, E> {
+                Ok(FileEntry::Name(name.to_string()))
+            }
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<FileEntry, A::Error> {
+                FileConfig::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                    .map(FileEntry::Detailed)
+            }
+        }
+        deserializer.deserialize_any(FileEntryVisitor)
+    }
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigFile {
+    /// Layout version, see `config_schema`.
+    pub version: u64,
     pub branches: HashMap<String, BranchConfig>,
     pub store_dir: String,
     pub repo_dir: String,
+    /// Ghidriff options for every branch.
+    #[serde(default, skip_serializing_if = "GhidriffOptions::is_empty")]
+    pub ghidriff: GhidriffOptions,
+    /// Never fetch, using the existing clones as they are.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub offline: bool,
+    /// Only fetch repositories last synced longer ago than this, ie. `6h`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_repo_age: Option<Age>,
+    /// The YAML this config was loaded from, used to point validation issues at a line.
+    #[serde(skip)]
+    pub(crate) source: String,
+}
+
+/// A duration written as a whole number followed by `s`, `m`, `h` or `d`, ie. `6h`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Age(pub Duration);
+impl std::str::FromStr for Age {
+    type Err = String;
+    fn from_str(age: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{age:?} is not an age like 90s, 30m, 6h or 2d");
+        let split = age.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let (count, unit) = age.split_at(split);
+        let count: u64 = count.parse().map_err(|_e| invalid())?;
+        let seconds = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        Ok(Self(Duration::from_secs(count.checked_mul(seconds).ok_or_else(invalid)?)))
+    }
+}
+impl Age {
+    /// `duration` rounded down to its largest whole unit, for display.
+    #[must_use]
+    pub fn rounded(duration: Duration) -> Self {
+        let seconds = duration.as_secs();
+        let size = [24 * 60 * 60, 60 * 60, 60]
+            .into_iter()
+            .find(|size| seconds >= *size)
+            .unwrap_or(1);
+        Self(Duration::from_secs(seconds / size * size))
+    }
+}
+impl TryFrom<String> for Age {
+    type Error = String;
+    fn try_from(age: String) -> Result<Self, Self::Error> {
+        age.parse()
+    }
+}
+impl std::fmt::Display for Age {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.0.as_secs();
+        for (unit, size) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)] {
+            if seconds > 0 && seconds.is_multiple_of(size) {
+                return write!(f, "{}{unit}", seconds / size);
+            }
+        }
+        write!(f, "{seconds}s")
+    }
+}
+impl From<Age> for String {
+    fn from(age: Age) -> Self {
+        age.to_string()
+    }
+}
+
+/// Lists the binaries with metadata in a Winbindex data directory, sorted by name.
+///
+/// # Errors
+/// Returns `FileIOError` if the directory can't be read.
+pub fn list_data_dir(data_path: &Path) -> Result<Vec<String>, ConfigFileError> {
+    let dir = std::fs::read_dir(data_path)
+        .map_err(|e| ConfigFileError::FileIOError(format!("{}: {e}", data_path.display())))?;
+    Ok(binary_names(dir.filter_map(Result::ok).filter_map(|entry| {
+        entry.file_name().to_str().map(str::to_string)
+    })))
+}
+
+/// Lists the binaries with metadata in `data_dir` of `commit`, or of the checked out commit of
+/// `repo_path` if unset, whether or not they are in the working tree.
+///
+/// # Errors
+/// Returns `GitError` if the repository can't be opened or `data_dir` is not in its tree.
+pub fn list_data_tree(
+    repo_path: &Path,
+    data_dir: &str,
+    commit: Option<git2::Oid>,
+) -> Result<Vec<String>, ConfigFileError> {
+    let repo = git2::Repository::open(repo_path)
+        .map_err(|e| ConfigFileError::GitError(GitError::LocalRepoIsBusted(GitCause::new(&e, repo_path))))?;
+    let entries = commit
+        .map_or_else(|| list_head_dir(&repo, data_dir), |commit| list_commit_dir(&repo, commit, data_dir))
+        .map_err(ConfigFileError::GitError)?;
+    Ok(binary_names(entries.into_iter()))
+}
+
+/// Strips `.json.gz` from metadata file names, dropping anything else, sorted by name.
+fn binary_names(file_names: impl Iterator<Item = String>) -> Vec<String> {
+    let mut names: Vec<String> = file_names
+        .filter_map(|name| Some(name.strip_suffix(".json.gz")?.to_string()))
+        .collect();
+    names.sort();
+    names
+}
+
+impl MetadataSource {
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl BranchConfig {
+    /// Whether the binaries of this branch are listed from the git tree rather than the working
+    /// tree, which may not hold all of them.
+    #[must_use]
+    pub fn lists_from_tree(&self) -> bool {
+        self.sparse || self.reads_from_git()
+    }
+
+    /// `data_dir` as a path in the repository's tree, as git reports it, ie. `./data/` becomes
+    /// `data`.
+    #[must_use]
+    pub fn git_data_dir(&self) -> String {
+        self.data_dir
+            .split(['/', '\\'])
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Path of the metadata of `binary` in the repository's tree, ie. `data/ntdll.dll.json.gz`.
+    #[must_use]
+    pub fn metadata_path(&self, binary: &str) -> String {
+        match self.git_data_dir().as_str() {
+            "" => format!("{binary}.json.gz"),
+            data_dir => format!("{data_dir}/{binary}.json.gz"),
+        }
+    }
+
+    /// Whether metadata is read from the git object database rather than the working tree.
+    #[must_use]
+    pub fn reads_from_git(&self) -> bool {
+        self.metadata_source == MetadataSource::Git || self.at.is_some()
+    }
+
+    /// Expands pattern entries against the binaries available in `data_path`. Explicitly named
+    /// binaries are kept as they are and take precedence over pattern matches, while `exclude`
+    /// only applies to pattern matches. The data directory is only read if there are patterns.
+    ///
+    /// # Errors
+    /// Fails if a pattern does not compile, or the data directory can't be read.
+    pub fn resolve_files(&self, data_path: &Path) -> Result<ResolvedFiles, ConfigFileError> {
+        self.resolve_files_from(|| list_data_dir(data_path))
+    }
+    /// As [`Self::resolve_files`], with the available binaries listed by `list_available`.
+    ///
+    /// # Errors
+    /// Fails if a pattern does not compile, or `list_available` fails.
+    pub fn resolve_files_from(
+        &self,
+        list_available: impl FnOnce() -> Result<Vec<String>, ConfigFileError>,
+    ) -> Result<ResolvedFiles, ConfigFileError> {
+        let mut list_available = Some(list_available);
+        let excludes = self
+            .exclude
+            .iter()
+            .map(|e| {
+                glob::Pattern::new(e)
+                    .map_err(|err| ConfigFileError::InvalidPattern(format!("exclude {e:?}: {err}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let configs: Vec<FileConfig> = self.files.iter().map(FileEntry::config).collect();
+        let mut resolved = ResolvedFiles::default();
+        let mut available: Option<Vec<String>> = None;
+
+        for config in configs.iter().filter(|c| c.glob.is_none() && c.regex.is_none()) {
+            if !resolved.files.iter().any(|f| f.name == config.name) {
+                resolved.files.push(config.clone());
+            }
+        }
+        for config in &configs {
+            let Some(pattern) = config.pattern()? else {
+                continue;
+            };
+            if let Some(list_available) = list_available.take() {
+                available = Some(list_available()?);
+            }
+            let mut matched = 0;
+            for name in available.iter().flatten() {
+                if !pattern.matches(name) || excludes.iter().any(|e| e.matches(name)) {
+                    continue;
+                }
+                matched += 1;
+                if !resolved.files.iter().any(|f| &f.name == name) {
+                    resolved.files.push(FileConfig {
+                        name: name.clone(),
+                        glob: None,
+                        regex: None,
+                        ..config.clone()
+                    });
+                }
+            }
+            resolved.patterns.push(PatternMatches {
+                pattern: config.label().to_string(),
+                matched,
+            });
+        }
+        Ok(resolved)
+    }
 }
 
 impl ConfigFile {
-    /// Pulls latest updates from Winbindex
+    /// Path to the Winbindex data directory within the local clone of a branch.
+    #[must_use]
+    pub fn data_path(&self, branch_name: &str) -> PathBuf {
+        let data_dir = self
+            .branches
+            .get(branch_name)
+            .map_or("", |branch| branch.data_dir.as_str());
+        Path::new(&self.repo_dir).join(branch_name).join(data_dir)
+    }
+    /// The Ghidriff options for a binary, layering the global, branch and file settings.
+    #[must_use]
+    pub fn ghidriff_options(&self, branch_name: &str, file: Option<&FileConfig>) -> GhidriffOptions {
+        let mut options = self.ghidriff.clone();
+        if let Some(branch) = self.branches.get(branch_name) {
+            options = options.merged(&branch.ghidriff);
+        }
+        if let Some(file) = file {
+            options = options.merged(&file.ghidriff);
+        }
+        options
+    }
+    /// Whether the branch has a local clone, bare or not.
+    #[must_use]
+    pub fn is_cloned(&self, branch_name: &str) -> bool {
+        git2::Repository::open(Path::new(&self.repo_dir).join(branch_name)).is_ok()
+    }
+    /// Checks that the branch has a local clone to read metadata from.
+    ///
+    /// # Errors
+    /// Returns `NotCloned` if it has none.
+    pub fn require_clone(&self, branch_name: &str) -> Result<(), ConfigFileError> {
+        if self.is_cloned(branch_name) {
+            Ok(())
+        } else {
+            Err(ConfigFileError::NotCloned(branch_name.to_string()))
+        }
+    }
+    /// Resolves the binaries tracked by a branch, listing them from the git tree where the
+    /// working tree may not hold all of them.
+    pub(crate) fn resolve_branch_files(
+        &self,
+        branch_name: &str,
+        branch: &BranchConfig,
+    ) -> Result<ResolvedFiles, ConfigFileError> {
+        if branch.lists_from_tree() {
+            let repo_path = Path::new(&self.repo_dir).join(branch_name);
+            let commit = self.snapshot_commit(branch_name)?;
+            branch.resolve_files_from(|| list_data_tree(&repo_path, &branch.git_data_dir(), commit))
+        } else {
+            branch.resolve_files(&self.data_path(branch_name))
+        }
+    }
+    /// The commit a branch's `at:` snapshot refers to, or `None` if the branch is not pinned.
+    ///
+    /// # Errors
+    /// Returns `GitError` if the clone can't be opened or the snapshot is not in its history.
+    pub fn snapshot_commit(&self, branch_name: &str) -> Result<Option<git2::Oid>, ConfigFileError> {
+        let Some(snapshot) = self.branches.get(branch_name).and_then(|branch| branch.at.as_ref()) else {
+            return Ok(None);
+        };
+        let repo = git2::Repository::open(Path::new(&self.repo_dir).join(branch_name))
+            .map_err(|_err| ConfigFileError::NotCloned(branch_name.to_string()))?;
+        resolve_snapshot(&repo, snapshot)
+            .map(Some)
+            .map_err(ConfigFileError::GitError)
+    }
+    /// Resolves the binaries tracked by a branch, logging how many binaries each pattern matched.
+    ///
+    /// # Errors
+    /// Fails if a pattern does not compile, or the data directory can't be read.
+    pub fn resolve_files(&self, branch_name: &str) -> Result<ResolvedFiles, ConfigFileError> {
+        let Some(branch) = self.branches.get(branch_name) else {
+            return Ok(ResolvedFiles::default());
+        };
+        let resolved = self.resolve_branch_files(branch_name, branch)?;
+        for pattern in &resolved.patterns {
+            log::info!("{branch_name}: {} matched {} binaries", pattern.pattern, pattern.matched);
+        }
+        Ok(resolved)
+    }
+    /// Why the clone of a branch should be left as it is, if it should.
+    fn skip_update_reason(&self, branch_name: &str) -> Result<Option<String>, ConfigFileError> {
+        if self.offline {
+            self.require_clone(branch_name)?;
+            return Ok(Some("running offline".to_string()));
+        }
+        let Some(max_age) = self.max_repo_age else {
+            return Ok(None);
+        };
+        let synced_ago = git2::Repository::open(Path::new(&self.repo_dir).join(branch_name))
+            .ok()
+            .and_then(|repo| last_synced(&repo))
+            .and_then(|synced| synced.elapsed().ok());
+        Ok(synced_ago
+            .filter(|ago| *ago < max_age.0)
+            .map(|ago| format!("it was synced {} ago", Age::rounded(ago))))
+    }
+    /// Pulls latest updates from Winbindex, unless running offline or the clone is newer than
+    /// `max_repo_age`.
+    ///
+    /// # Errors
+    /// Returns `NotCloned` when offline without a clone, otherwise the first `GitError`
+    /// encountered.
     pub fn update_repos(&self) -> Result<(), ConfigFileError> {
         for (k, v) in &self.branches {
-            let helper = GitHelper::new(Path::new(&self.repo_dir), &v.branch, &v.repo_url, k);
-            helper
-                .clone_or_pull()
-                .map_err(ConfigFileError::GitError)?;
+            let helper = GitHelper::new(Path::new(&self.repo_dir), &v.branch, &v.repo_url, k)
+                .with_depth(v.depth)
+                .with_sparse(v.sparse)
+                .with_bare(v.metadata_source == MetadataSource::Git)
+                .with_rewrite_policy(v.on_rewrite)
+                .with_auth(v.auth.clone())
+                .with_proxy(v.proxy.clone())
+                .with_self_heal(v.self_heal);
+            let repo_path = Path::new(&self.repo_dir).join(k);
+            let repo = match self.skip_update_reason(k)? {
+                Some(reason) => {
+                    log::info!("Not updating {k}, {reason}");
+                    git2::Repository::open(&repo_path).map_err(|e| {
+                        ConfigFileError::GitError(GitError::LocalRepoIsBusted(GitCause::new(&e, &repo_path)))
+                    })?
+                }
+                None => helper
+                    .clone_or_pull()
+                    .map_err(ConfigFileError::GitError)?,
+            };
+            if v.sparse && !repo.is_bare() {
+                let paths: Vec<String> = self
+                    .resolve_files(k)?
+                    .files
+                    .iter()
+                    .map(|f| v.metadata_path(&f.name))
+                    .collect();
+                helper
+                    .sparse_checkout(&repo, &paths)
+                    .map_err(ConfigFileError::GitError)?;
+            }
         }
         Ok(())
     }
-    /// Opens a config file, or creates one if it does not exist.
-    pub(crate) fn open_or_create(path: &Path) -> Result<Self, ConfigFileError> {
-        let mut config_file_result = File::open(path);
-        let config: Result<Self, ConfigFileError>;
-        if config_file_result.is_err() {
-            config_file_result = File::create(path);
-            let config_file_result = match config_file_result {
-                Ok(file) => file,
-                Err(_e) => return Err(ConfigFileError::FileIOError()),
-            };
-
-            config = Ok(Self {
-                branches: HashMap::new(),
-                store_dir: "../sample/store".to_string(),
-                repo_dir: "../sample/repos".to_string(),
-            });
-            let serde_result = serde_yaml::to_writer(
-                config_file_result,
-                &config.clone().map_err(|_e|ConfigFileError::ConfigFileCreation)?,
-            );
-            match serde_result {
-                Ok(_config_file) => config,
-                Err(_e) => Err(ConfigFileError::ConfigFileCreation),
-            }
+    /// Parses a config from YAML, migrating older layouts. `origin` names the source in error
+    /// messages.
+    ///
+    /// # Errors
+    /// Returns `ImproperlyFormattedConfigFile` on syntax errors and unknown keys, and
+    /// `UnsupportedVersion` for layouts newer than this build.
+    pub fn parse(source: &str, origin: &str) -> Result<Self, ConfigFileError> {
+        let mut value: serde_yaml::Value = serde_yaml::from_str(source)
+            .map_err(|e| ConfigFileError::ImproperlyFormattedConfigFile(format!("{origin}: {e}")))?;
+        let serde_result = if migrate_with_warning(&mut value, origin)? == CURRENT_VERSION {
+            // Parse the text itself so errors keep their line numbers.
+            serde_yaml::from_str::<Self>(source)
         } else {
-            let serde_result: Result<Self, serde_yaml::Error> =
-                serde_yaml::from_reader(config_file_result.map_err(|_e|ConfigFileError::ConfigFileCreation)?);
-            match serde_result {
-                Ok(config_file) => Ok(config_file),
-                Err(_e) => Err(ConfigFileError::ImproperlyFormattedConfigFile),
-            }
+            serde_yaml::from_value(value)
+        };
+        match serde_result {
+            Ok(config_file) => Ok(Self {
+                source: source.to_string(),
+                ..config_file
+            }),
+            Err(e) => Err(ConfigFileError::ImproperlyFormattedConfigFile(format!("{origin}: {e}"))),
+        }
+    }
+    /// Opens an existing config file.
+    ///
+    /// # Errors
+    /// Returns `NotFound` if the file does not exist, and fails if it can't be read or parsed.
+    pub fn open(path: &Path) -> Result<Self, ConfigFileError> {
+        if !path.exists() {
+            return Err(ConfigFileError::NotFound(path.display().to_string()));
+        }
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| ConfigFileError::FileIOError(format!("{}: {e}", path.display())))?;
+        Self::parse(&source, &path.display().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages_parse() {
+        assert_eq!("90s".parse(), Ok(Age(Duration::from_secs(90))));
+        assert_eq!("6h".parse(), Ok(Age(Duration::from_hours(6))));
+        assert_eq!("2d".parse(), Ok(Age(Duration::from_hours(48))));
+        for invalid in ["", "6", "h", "6w", "-6h", "6 h"] {
+            assert!(invalid.parse::<Age>().is_err(), "{invalid:?}");
         }
     }
+
+    #[test]
+    fn overflowing_ages_are_rejected() {
+        let age = format!("{}d", u64::MAX);
+        assert_eq!(age.parse::<Age>(), Err(format!("{age:?} is not an age like 90s, 30m, 6h or 2d")));
+        assert!(format!("{}s", u64::MAX).parse::<Age>().is_ok());
+    }
+
+    #[test]
+    fn selector_hashes_follow_the_filters() {
+        let file = FileConfig {
+            name: "a.dll".to_string(),
+            arches: vec![Arch::Amd64, Arch::Arm64],
+            ..FileConfig::default()
+        };
+        let same = FileConfig {
+            name: "b.dll".to_string(),
+            arches: vec![Arch::Arm64, Arch::Amd64],
+            ghidriff: GhidriffOptions { sxs: Some(true), ..GhidriffOptions::default() },
+            ..FileConfig::default()
+        };
+        assert_eq!(file.selector_hash(), same.selector_hash());
+        assert_eq!(file.selector_hash().len(), 16);
+        let changed = [
+            FileConfig { arches: vec![Arch::Amd64], ..file.clone() },
+            FileConfig { min_version: BinaryVersion::parse("10.0.1.1"), ..file.clone() },
+            FileConfig { max_version: BinaryVersion::parse("10.0.1.1"), ..file.clone() },
+        ];
+        for changed in changed {
+            assert_ne!(file.selector_hash(), changed.selector_hash());
+        }
+    }
+
+    #[test]
+    fn offline_branches_must_be_cloned() {
+        let source = [
+            "version: 2",
+            "store_dir: s",
+            "repo_dir: /nonexistent",
+            "offline: true",
+            "branches:",
+            "  main: {repo_url: u, branch: b, data_dir: data, files: [ntdll.dll]}",
+        ]
+        .join("\n");
+        let config_file = ConfigFile::parse(&source, "test").unwrap();
+        let not_cloned = config_file.require_clone("main");
+        assert!(matches!(not_cloned, Err(ConfigFileError::NotCloned(b)) if b == "main"));
+        assert!(matches!(config_file.skip_update_reason("main"), Err(ConfigFileError::NotCloned(_))));
+    }
 }
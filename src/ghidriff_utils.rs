@@ -3,8 +3,9 @@
 use std::{ fs::File, io::copy, path::{Path, PathBuf}, process::Command};
 
 use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 
-use crate::winbindex_utils::{Arch, WinbindexEntry};
+use crate::winbindex_utils::{Arch, Provenance, WinbindexEntry};
 
 extern crate reqwest;
 #[derive(Debug)]
@@ -19,17 +20,142 @@ pub enum GhidriffError{
     FileWrite(String),
 }
 
+/// Diffing engines supported by Ghidriff.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GhidriffEngine {
+    #[serde(rename = "SimpleDiff")]
+    Simple,
+    #[serde(rename = "StructualGraphDiff")]
+    StructualGraph,
+    #[serde(rename = "VersionTrackingDiff")]
+    VersionTracking,
+}
+impl From<GhidriffEngine> for String {
+    fn from(val: GhidriffEngine) -> Self {
+        match val {
+            GhidriffEngine::Simple => "SimpleDiff".to_owned(),
+            GhidriffEngine::StructualGraph => "StructualGraphDiff".to_owned(),
+            GhidriffEngine::VersionTracking => "VersionTrackingDiff".to_owned(),
+        }
+    }
+}
+
+/// Options controlling how Ghidriff is invoked. These can be set globally, per branch and per
+/// file, with the most specific setting winning and `extra_args` accumulating.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GhidriffOptions {
+    /// Diffing engine, `VersionTrackingDiff` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine: Option<GhidriffEngine>,
+    /// Passes `--force-analysis`, on when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub force_analysis: Option<bool>,
+    /// Passes `--sxs` to produce side by side diffs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sxs: Option<bool>,
+    /// Symbol store passed as `--symbols-path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbols_path: Option<String>,
+    /// Share of system memory the Ghidra JVM may use, passed as `--max-ram-percent`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_ram_percent: Option<u32>,
+    /// Extra JVM arguments, passed as `--jvm-args`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jvm_args: Option<String>,
+    /// Arguments appended to the Ghidriff command line, before the binaries.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+}
+
+impl GhidriffOptions {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+    /// Layers `more_specific` on top of these options.
+    #[must_use]
+    pub fn merged(&self, more_specific: &Self) -> Self {
+        Self {
+            engine: more_specific.engine.or(self.engine),
+            force_analysis: more_specific.force_analysis.or(self.force_analysis),
+            sxs: more_specific.sxs.or(self.sxs),
+            symbols_path: more_specific.symbols_path.clone().or_else(|| self.symbols_path.clone()),
+            max_ram_percent: more_specific.max_ram_percent.or(self.max_ram_percent),
+            jvm_args: more_specific.jvm_args.clone().or_else(|| self.jvm_args.clone()),
+            extra_args: self.extra_args.iter().chain(&more_specific.extra_args).cloned().collect(),
+        }
+    }
+    /// Builds the Ghidriff arguments for these options, excluding paths and binaries.
+    #[must_use]
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.force_analysis.unwrap_or(true) {
+            args.push("--force-analysis".to_string());
+        }
+        args.push("--engine".to_string());
+        args.push(self.engine.unwrap_or(GhidriffEngine::VersionTracking).into());
+        if self.sxs.unwrap_or(false) {
+            args.push("--sxs".to_string());
+        }
+        if let Some(symbols_path) = &self.symbols_path {
+            args.push("--symbols-path".to_string());
+            args.push(symbols_path.clone());
+        }
+        if let Some(max_ram_percent) = self.max_ram_percent {
+            args.push("--max-ram-percent".to_string());
+            args.push(max_ram_percent.to_string());
+        }
+        if let Some(jvm_args) = &self.jvm_args {
+            args.push("--jvm-args".to_string());
+            args.push(jvm_args.clone());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+/// One side of a diff, as recorded in the `.provenance.json` file written next to its output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiffSideProvenance {
+    pub sha256: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Provenance>,
+}
+impl From<&WinbindexEntry> for DiffSideProvenance {
+    fn from(entry: &WinbindexEntry) -> Self {
+        Self {
+            sha256: entry.get_sha256().unwrap_or_default(),
+            version: entry.get_version().to_string(),
+            source: entry.provenance.clone(),
+        }
+    }
+}
+
+/// Where the binaries of a diff and their metadata came from, written next to the diff output as
+/// `<old>-<new>.provenance.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiffProvenance {
+    pub old: DiffSideProvenance,
+    pub new: DiffSideProvenance,
+}
+
 pub struct GhidriffDiffingProject {
     store_path: PathBuf,
     winbindex_instance: String,
     binary_name: String,
     arch: Arch,
+    options: GhidriffOptions,
 }
 
 
 
 /// Downloads a given `WinbindexEntry` to the provided path. Note that the filename is derived from
 /// the `WinbindexEntry`, and is not controllable.
+///
+/// # Errors
+/// Fails if the entry has no download URL, or the download or write fails.
 pub async fn download_binary(path: &Path, winbindex_entry:&WinbindexEntry) -> Result<(), GhidriffError>{
     let url = winbindex_entry.get_download_url().ok_or(GhidriffError::WinbindexEntryNoURL)?;
     let response = reqwest::get(url.url).await.map_err(GhidriffError::Reqwest)?;
@@ -48,6 +174,7 @@ pub async fn download_binary(path: &Path, winbindex_entry:&WinbindexEntry) -> Re
 }
 
 impl GhidriffDiffingProject {
+    #[must_use]
     pub fn new(
         store_path: PathBuf,
         winbindex_instance: &str,
@@ -58,12 +185,56 @@ impl GhidriffDiffingProject {
             store_path,
             winbindex_instance: winbindex_instance.to_string(),
             binary_name: binary_name.to_string(),
-            arch
+            arch,
+            options: GhidriffOptions::default(),
+        }
+    }
+    /// Sets the options used when invoking Ghidriff.
+    #[must_use]
+    pub fn with_options(mut self, options: GhidriffOptions) -> Self {
+        self.options = options;
+        self
+    }
+    /// Writes the provenance of the `old` and `new` pair next to their diff output.
+    fn write_provenance(diff_folder: &Path, old: &WinbindexEntry, new: &WinbindexEntry) -> Option<()> {
+        let path = diff_folder.join(format!(
+            "{}-{}.provenance.json",
+            old.get_binary_dlname()?,
+            new.get_binary_dlname()?
+        ));
+        let provenance = DiffProvenance { old: old.into(), new: new.into() };
+        let written = File::create(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::to_writer_pretty(file, &provenance).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            log::warn!("Could not write {}: {e}", path.display());
         }
+        Some(())
     }
     /// Diffs all provided `WinbindexEntry` on a 2-wide sliding window basis. 
     /// ie. entries[0] + entries[1] will be diffed, but so will entries[1] + entries[2]
-    pub async fn run_diff_on_all(&self, entries: &Vec<WinbindexEntry>) -> Result<(),GhidriffError> {
+    ///
+    /// # Errors
+    /// See [`Self::run_diff_on_pairs`].
+    ///
+    /// # Panics
+    /// See [`Self::run_diff_on_pairs`].
+    pub async fn run_diff_on_all(&self, entries: &[WinbindexEntry]) -> Result<(),GhidriffError> {
+        let pairs: Vec<_> = entries
+            .windows(2)
+            .map(|chunk| (chunk[0].clone(), chunk[1].clone()))
+            .collect();
+        self.run_diff_on_pairs(&pairs).await
+    }
+    /// Diffs each provided (old, new) pair of `WinbindexEntry`, recording where each pair came
+    /// from in a `.provenance.json` file next to its diff. Download failures are logged and skipped.
+    ///
+    /// # Errors
+    /// Fails if the download, project or diff directories can't be created.
+    ///
+    /// # Panics
+    /// Panics if the `ghidriff` executable can't be run.
+    pub async fn run_diff_on_pairs(&self, pairs: &[(WinbindexEntry, WinbindexEntry)]) -> Result<(),GhidriffError> {
         //1. Make temporary directory for binaries
         //2. Download all binaries
         //3. Create a temporary Ghidra project path
@@ -72,17 +243,23 @@ impl GhidriffDiffingProject {
         //  <store_path>/diffs/<branch>/<filename>/<arch>/<old>-<new>.[md|json]ßßß
         //5. Build ghidriff command with all binary paths
         //6. Run command
-        if entries.is_empty(){ 
-            println!("Nothing to diff!");
+        if pairs.is_empty(){ 
+            log::info!("Nothing to diff!");
             return Ok(());
         }
+        let mut entries: Vec<&WinbindexEntry> = Vec::new();
+        for entry in pairs.iter().flat_map(|(old, new)| [old, new]) {
+            if !entries.iter().any(|e| e.get_sha256() == entry.get_sha256()) {
+                entries.push(entry);
+            }
+        }
         //[1]
         let binary_download_path = self.store_path.join("binaries").join(&self.winbindex_instance).join(&self.binary_name);
         std::fs::create_dir_all(&binary_download_path).map_err(|_e|GhidriffError::BinaryDownloadDirectoryCreation)?;
         
         //[2]
         let fetches = futures::stream::iter(
-            entries.iter().map(|entry| {
+            entries.into_iter().map(|entry| {
                 async move {
                     let binary_download_path = self.store_path.join("binaries").join(&self.winbindex_instance).join(&self.binary_name);
                     if let Some(_get_download_url) = entry.get_download_url(){
@@ -90,7 +267,7 @@ impl GhidriffDiffingProject {
                             Ok(()) => {
                             }
                             Err(e) => {
-                                println!("{:?} | ERROR downloading {}", e, entry.get_download_url()?.url);
+                                log::error!("{:?} | ERROR downloading {}", e, entry.get_download_url()?.url);
                             },
                         }
                     }
@@ -111,12 +288,10 @@ impl GhidriffDiffingProject {
         std::fs::create_dir_all(diff_folder).map_err(|_e|GhidriffError::DiffProjectDirectoryCreation)?;
         
         let ghidra_runs = futures::stream::iter(
-            entries.as_slice().windows(2).map(|chunk| {
+            pairs.iter().map(|(old, new)| {
                 let ghidra_projects_path = ghidra_projects_path.clone();
                 let binary_download_path = binary_download_path.clone();
                 async move {
-                    let old = &chunk[0];
-                    let new = &chunk[1];
                     let old_fname = old.get_binary_dlname()?;
                     let new_fname = new.get_binary_dlname()?;
                     //[5 + 6]
@@ -126,13 +301,11 @@ impl GhidriffDiffingProject {
                     .arg(ghidra_projects_path.to_str()?)
                     .arg("-o")
                     .arg(diff_folder.to_str()?)
-                    .arg("--force-analysis")
-                    .arg("--engine")
-                    .arg("VersionTrackingDiff")
+                    .args(self.options.to_args())
                     .arg(binary_download_path.join(old_fname).to_str()?)
                     .arg(binary_download_path.join(new_fname).to_str()?)
                     .status().expect("Could not run Ghidriff");
-                    Some(())
+                    Self::write_provenance(diff_folder, old, new)
                 }
         })
         ).buffer_unordered(8).collect::<Vec<Option<()>>>();
@@ -1,29 +1,240 @@
 //! Manages various Git operations that are needed for the project
 
-use git2::build::RepoBuilder;
-use git2::Repository;
-use std::path::Path;
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{Cred, CredentialType, FetchOptions, ObjectType, Oid, ProxyOptions, RemoteCallbacks, Repository};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// libgit2's `GIT_FETCH_DEPTH_UNSHALLOW`, fetching the history missing from a shallow clone.
+const UNSHALLOW: i32 = i32::MAX;
+/// Records the paths materialised by a sparse checkout, relative to the `.git` directory.
+const SPARSE_SET: &str = "info/winbindex_differ_sparse";
+/// Touched after every successful clone or pull, relative to the `.git` directory.
+const SYNCED_MARKER: &str = "info/winbindex_differ_synced";
+
+/// The underlying `git2::Error` of a [`GitError`], along with the path it concerns.
+#[derive(Debug, Clone)]
+pub struct GitCause {
+    pub message: String,
+    pub class: git2::ErrorClass,
+    pub code: git2::ErrorCode,
+    pub path: Option<PathBuf>,
+}
+impl GitCause {
+    #[must_use]
+    pub fn new(error: &git2::Error, path: &Path) -> Self {
+        Self {
+            message: error.message().to_string(),
+            class: error.class(),
+            code: error.code(),
+            path: Some(path.to_path_buf()),
+        }
+    }
+    /// Wraps a filesystem error, reported with the `Os` class.
+    #[must_use]
+    pub fn io(error: &std::io::Error, path: &Path) -> Self {
+        Self {
+            message: error.to_string(),
+            class: git2::ErrorClass::Os,
+            code: git2::ErrorCode::GenericError,
+            path: Some(path.to_path_buf()),
+        }
+    }
+    /// Whether this points at damage to the local repository rather than a network or
+    /// configuration problem.
+    #[must_use]
+    pub const fn is_corruption(&self) -> bool {
+        use git2::ErrorClass::{Index, Object, Odb, Repository, Zlib};
+        matches!(self.class, Odb | Object | Zlib | Index | Repository)
+    }
+}
+impl std::fmt::Display for GitCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?} error", self.message, self.class)?;
+        if let Some(path) = &self.path {
+            write!(f, " in {}", path.display())?;
+        }
+        write!(f, ")")
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum GitError {
-    FailedRepoClone,
-    RepoFetchFailed,
-    CouldNotFindRemote(String),
-    CouldNotFindReference,
-    MergeAnalysisFailed,
-    CouldNotSetTarget,
-    SetHeadFailure,
-    CheckoutFailure,
-    LocalRepoIsBusted,
+    FailedRepoClone(GitCause),
+    RepoFetchFailed(GitCause),
+    CouldNotFindRemote(String, GitCause),
+    CouldNotFindReference(GitCause),
+    MergeAnalysisFailed(GitCause),
+    CouldNotSetTarget(GitCause),
+    SetHeadFailure(GitCause),
+    CheckoutFailure(GitCause),
+    /// An existing clone can't be opened or is damaged.
+    LocalRepoIsBusted(GitCause),
+    PathNotInTree(String),
+    /// The remote branch no longer contains the local one.
+    HistoryRewritten,
+    /// A pinned snapshot could not be found in the local history.
+    SnapshotNotFound(String),
+}
+impl GitError {
+    /// The git2 or filesystem error behind this one, if any.
+    #[must_use]
+    pub const fn cause(&self) -> Option<&GitCause> {
+        match self {
+            Self::FailedRepoClone(cause)
+            | Self::RepoFetchFailed(cause)
+            | Self::CouldNotFindRemote(_, cause)
+            | Self::CouldNotFindReference(cause)
+            | Self::MergeAnalysisFailed(cause)
+            | Self::CouldNotSetTarget(cause)
+            | Self::SetHeadFailure(cause)
+            | Self::CheckoutFailure(cause)
+            | Self::LocalRepoIsBusted(cause) => Some(cause),
+            Self::PathNotInTree(_) | Self::HistoryRewritten | Self::SnapshotNotFound(_) => None,
+        }
+    }
+    /// Whether the local clone is damaged, and cloning it again may help.
+    #[must_use]
+    pub fn is_broken_clone(&self) -> bool {
+        matches!(self, Self::LocalRepoIsBusted(_)) || self.cause().is_some_and(GitCause::is_corruption)
+    }
+}
+impl std::fmt::Display for GitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FailedRepoClone(cause) => write!(f, "clone failed: {cause}"),
+            Self::RepoFetchFailed(cause) => write!(f, "fetch failed: {cause}"),
+            Self::CouldNotFindRemote(name, cause) => write!(f, "remote {name} not found: {cause}"),
+            Self::CouldNotFindReference(cause) => write!(f, "reference not found: {cause}"),
+            Self::MergeAnalysisFailed(cause) => write!(f, "merge analysis failed: {cause}"),
+            Self::CouldNotSetTarget(cause) => write!(f, "could not move the branch: {cause}"),
+            Self::SetHeadFailure(cause) => write!(f, "could not set HEAD: {cause}"),
+            Self::CheckoutFailure(cause) => write!(f, "checkout failed: {cause}"),
+            Self::LocalRepoIsBusted(cause) => write!(f, "local clone is broken: {cause}"),
+            Self::PathNotInTree(path) => write!(f, "{path} is not a directory in the tree"),
+            Self::HistoryRewritten => write!(f, "the remote branch was rewritten and no longer contains the local one"),
+            Self::SnapshotNotFound(why) => write!(f, "snapshot not found: {why}"),
+        }
+    }
+}
+
+/// What to do when the remote branch was force-pushed and no longer contains the local one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RewritePolicy {
+    /// Fail with `GitError::HistoryRewritten`, leaving the clone as it is.
+    #[default]
+    Fail,
+    /// Hard reset the local branch to the remote one.
+    Reset,
+    /// Delete the clone and clone it again.
+    Reclone,
+}
+impl RewritePolicy {
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// How to authenticate against a branch's remote. Each method is tried when the remote accepts
+/// that kind of credential, in the order the fields are listed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GitAuth {
+    /// Private key for SSH remotes, `~` expands to the home directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<String>,
+    /// Environment variable holding the passphrase of `ssh_key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_key_passphrase_env: Option<String>,
+    /// Ask the running SSH agent for a key.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ssh_agent: bool,
+    /// Environment variable holding a token used as the password for HTTPS remotes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_env: Option<String>,
+    /// Username sent along with `token_env`, `x-access-token` unless set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Use the credential helper configured in git, ie. `credential.helper`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub credential_helper: bool,
+}
+impl GitAuth {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+    /// `ssh_key` with a leading `~` expanded.
+    #[must_use]
+    pub fn ssh_key_path(&self) -> Option<PathBuf> {
+        let key = self.ssh_key.as_deref()?;
+        match (key.strip_prefix("~/"), std::env::var_os("HOME")) {
+            (Some(rest), Some(home)) => Some(Path::new(&home).join(rest)),
+            _ => Some(PathBuf::from(key)),
+        }
+    }
+    /// Picks a credential for `url` among the `allowed` kinds.
+    fn credential(
+        &self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        let env = |name: &str| {
+            std::env::var(name)
+                .map_err(|_err| git2::Error::from_str(&format!("environment variable {name} is not set")))
+        };
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username_from_url.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            let user = username_from_url.unwrap_or("git");
+            if let Some(key) = self.ssh_key_path() {
+                let passphrase = self.ssh_key_passphrase_env.as_deref().map(env).transpose()?;
+                return Cred::ssh_key(user, None, &key, passphrase.as_deref());
+            }
+            if self.ssh_agent {
+                return Cred::ssh_key_from_agent(user);
+            }
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(token_env) = &self.token_env {
+                let username = self.username.as_deref().or(username_from_url).unwrap_or("x-access-token");
+                return Cred::userpass_plaintext(username, &env(token_env)?);
+            }
+            if self.credential_helper {
+                let config = git2::Config::open_default()?;
+                return Cred::credential_helper(&config, url, username_from_url);
+            }
+        }
+        if allowed.contains(CredentialType::DEFAULT) {
+            return Cred::default();
+        }
+        Err(git2::Error::from_str(&format!("no configured credentials are accepted by {url}")))
+    }
 }
 
+/// Attempts made at authenticating a single operation before giving up, as libgit2 keeps asking
+/// for as long as credentials are rejected.
+const MAX_AUTH_ATTEMPTS: u32 = 3;
+
 pub struct GitHelper<'a> {
     repo_dir: &'a Path,
     branch_name: &'a String,
     url: &'a String,
     repo_name: &'a String,
+    depth: Option<u32>,
+    sparse: bool,
+    bare: bool,
+    on_rewrite: RewritePolicy,
+    auth: GitAuth,
+    proxy: Option<String>,
+    self_heal: bool,
 }
 impl<'a> GitHelper<'a> {
+    #[must_use]
     pub const fn new(
         repository_path: &'a Path,
         branch_name: &'a String,
@@ -35,67 +246,641 @@ impl<'a> GitHelper<'a> {
             branch_name,
             url: repo_url,
             repo_name,
+            depth: None,
+            sparse: false,
+            bare: false,
+            on_rewrite: RewritePolicy::Fail,
+            auth: GitAuth {
+                ssh_key: None,
+                ssh_key_passphrase_env: None,
+                ssh_agent: false,
+                token_env: None,
+                username: None,
+                credential_helper: false,
+            },
+            proxy: None,
+            self_heal: false,
         }
     }
-    pub fn pull(repo: &Repository, branch_name: &String) -> Result<(), GitError> {
+    /// Limits clones and fetches to the last `depth` commits. `None` fetches full history,
+    /// deepening an existing shallow clone.
+    #[must_use]
+    pub const fn with_depth(mut self, depth: Option<u32>) -> Self {
+        self.depth = depth;
+        self
+    }
+    /// Only checks out the paths passed to [`Self::sparse_checkout`], rather than the whole tree.
+    /// Turning this off on an existing sparse clone checks out everything on the next pull.
+    #[must_use]
+    pub const fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+    /// Makes new clones bare, for when metadata is read from the object database. Existing
+    /// clones are left as they are.
+    #[must_use]
+    pub const fn with_bare(mut self, bare: bool) -> Self {
+        self.bare = bare;
+        self
+    }
+    /// Sets how a force-pushed remote branch is handled when pulling.
+    #[must_use]
+    pub const fn with_rewrite_policy(mut self, on_rewrite: RewritePolicy) -> Self {
+        self.on_rewrite = on_rewrite;
+        self
+    }
+    /// Sets the credentials offered to the remote.
+    #[must_use]
+    pub fn with_auth(mut self, auth: GitAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+    /// Routes fetches through a proxy, or the one git is configured with when `auto`.
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: Option<String>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+    /// Moves a clone that can't be opened or is damaged aside and clones it again, instead of
+    /// failing with `LocalRepoIsBusted`.
+    #[must_use]
+    pub const fn with_self_heal(mut self, self_heal: bool) -> Self {
+        self.self_heal = self_heal;
+        self
+    }
+    /// Fetch options honouring the configured depth, credentials and proxy. `repo` is the
+    /// existing clone, if any.
+    fn fetch_options(&self, repo: Option<&Repository>) -> FetchOptions<'static> {
+        let mut fetch_options = FetchOptions::new();
+        let mut callbacks = RemoteCallbacks::new();
+        let auth = self.auth.clone();
+        let mut attempts = 0;
+        callbacks.credentials(move |url, username_from_url, allowed| {
+            attempts += 1;
+            if attempts > MAX_AUTH_ATTEMPTS {
+                return Err(git2::Error::from_str(&format!("authentication to {url} failed")));
+            }
+            auth.credential(url, username_from_url, allowed)
+        });
+        fetch_options.remote_callbacks(callbacks);
+        if let Some(proxy) = &self.proxy {
+            let mut proxy_options = ProxyOptions::new();
+            if proxy == "auto" {
+                proxy_options.auto();
+            } else {
+                proxy_options.url(proxy);
+            }
+            fetch_options.proxy_options(proxy_options);
+        }
+        match self.depth {
+            Some(depth) => {
+                fetch_options.depth(i32::try_from(depth).unwrap_or(i32::MAX));
+            }
+            None if repo.is_some_and(Repository::is_shallow) => {
+                log::info!("Fetching full history of shallow clone {}", self.repo_name);
+                fetch_options.depth(UNSHALLOW);
+            }
+            None => {}
+        }
+        fetch_options
+    }
+    /// Fetches the branch from `origin` and fast-forwards the local branch to it, returning the
+    /// paths that changed between the old and new commit.
+    ///
+    /// Shallow clones lack the history to prove a fast-forward, so they are moved to the
+    /// fetched commit whenever it differs. Other clones are reset to it if the rewrite policy
+    /// is `Reset`.
+    ///
+    /// # Errors
+    /// Returns `HistoryRewritten` if the remote branch was rewritten and the policy isn't
+    /// `Reset`, and fails if the fetch fails or the local branch can't be moved.
+    pub fn pull(&self, repo: &Repository) -> Result<Vec<String>, GitError> {
+        let branch_name = self.branch_name;
         let mut remote = repo
             .find_remote("origin")
-            .map_err(|_err| GitError::CouldNotFindRemote("origin".to_string()))?;
+            .map_err(|e| GitError::CouldNotFindRemote("origin".to_string(), GitCause::new(&e, repo.path())))?;
         remote
-            .fetch(&[&branch_name], None, None)
-            .map_err(|_err| GitError::RepoFetchFailed)?;
+            .fetch(&[branch_name], Some(&mut self.fetch_options(Some(repo))), None)
+            .map_err(|e| GitError::RepoFetchFailed(GitCause::new(&e, repo.path())))?;
 
         let fetch_head = repo
             .find_reference("FETCH_HEAD")
-            .map_err(|_err| GitError::CouldNotFindReference)?;
+            .map_err(|e| GitError::CouldNotFindReference(GitCause::new(&e, repo.path())))?;
         let fetch_commit = repo
             .reference_to_annotated_commit(&fetch_head)
-            .map_err(|_err| GitError::CouldNotFindReference)?;
+            .map_err(|e| GitError::CouldNotFindReference(GitCause::new(&e, repo.path())))?;
         let analysis = repo
             .merge_analysis(&[&fetch_commit])
-            .map_err(|_err| GitError::MergeAnalysisFailed)?;
+            .map_err(|e| GitError::MergeAnalysisFailed(GitCause::new(&e, repo.path())))?;
         if analysis.0.is_up_to_date() {
-            Ok(())
-        } else if analysis.0.is_fast_forward() {
+            Ok(Vec::new())
+        } else if analysis.0.is_fast_forward()
+            || repo.is_shallow()
+            || self.on_rewrite == RewritePolicy::Reset
+        {
+            if !analysis.0.is_fast_forward() && !repo.is_shallow() {
+                log::warn!("{} was rewritten upstream, resetting to {}", self.repo_name, fetch_commit.id());
+            }
             let refname = format!("refs/heads/{branch_name}");
             let mut reference = repo
                 .find_reference(&refname)
-                .map_err(|_err| GitError::CouldNotFindReference)?;
+                .map_err(|e| GitError::CouldNotFindReference(GitCause::new(&e, repo.path())))?;
+            let changed = reference
+                .target()
+                .map_or_else(|| Ok(Vec::new()), |old| changed_paths(repo, old, fetch_commit.id()))?;
             reference
                 .set_target(fetch_commit.id(), "Fast-Forward")
-                .map_err(|_err| GitError::CouldNotSetTarget)?;
+                .map_err(|e| GitError::CouldNotSetTarget(GitCause::new(&e, repo.path())))?;
             repo.set_head(&refname)
-                .map_err(|_err| GitError::SetHeadFailure)?;
-            repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
-                .map_err(|_err| GitError::CheckoutFailure)
+                .map_err(|e| GitError::SetHeadFailure(GitCause::new(&e, repo.path())))?;
+            if !repo.is_bare() {
+                repo.checkout_head(Some(&mut self.checkout_options(repo)))
+                    .map_err(|e| GitError::CheckoutFailure(GitCause::new(&e, repo.path())))?;
+            }
+            Ok(changed)
         } else {
-            Err(GitError::RepoFetchFailed)
+            Err(GitError::HistoryRewritten)
         }
     }
 
     ///
     /// Clones a git repository, or pulls it if it already exists.
     ///
+    /// # Errors
+    /// Fails if the repository can't be cloned or pulled. An existing clone that can't be opened
+    /// fails with `LocalRepoIsBusted`, unless self healing is on.
     pub fn clone_or_pull(&self) -> Result<Repository, GitError> {
+        let repo = self.clone_or_pull_unmarked()?;
+        let marker = repo.path().join(SYNCED_MARKER);
+        if let Some(info) = marker.parent() {
+            let _ = std::fs::create_dir_all(info);
+        }
+        let _ = std::fs::write(marker, "");
+        Ok(repo)
+    }
+
+    fn clone_or_pull_unmarked(&self) -> Result<Repository, GitError> {
         // First try and create the repo storage location.
         let _ = std::fs::create_dir_all(self.repo_dir);
         let clone_path = &self.repo_dir.join(self.repo_name);
-        // Check if it exists first before attempting a full clone;
-        let repo = Repository::open(clone_path);
-
-        if repo.is_err() {
-            println!("Cloning {}, branch {}", self.url, self.branch_name);
-            let repo = RepoBuilder::new()
-                .branch(self.branch_name.as_str())
-                .clone(self.url, clone_path).map_err(|_|GitError::CheckoutFailure)?;
-            Ok(repo)
-        } else {
-            println!("pulling {}, branch {}", self.url, self.branch_name);
+        // Clone into a missing or empty directory, anything else should be a clone to pull.
+        if clone_path.read_dir().map_or(true, |mut entries| entries.next().is_none()) {
+            return self.clone(clone_path);
+        }
+        let pulled = Repository::open(clone_path)
+            .map_err(|e| GitError::LocalRepoIsBusted(GitCause::new(&e, clone_path)))
+            .and_then(|repo| self.pull_existing(repo, clone_path));
+        match pulled {
+            Err(e) if self.self_heal && e.is_broken_clone() => {
+                let aside = move_aside(clone_path)?;
+                log::warn!("{}: {e}, moved it to {} and cloning it again", self.repo_name, aside.display());
+                self.clone(clone_path)
+            }
+            pulled => pulled,
+        }
+    }
+
+    /// Pulls the existing clone `repo` at `clone_path`, cloning again if it was rewritten and
+    /// the policy is `Reclone`.
+    fn pull_existing(&self, repo: Repository, clone_path: &Path) -> Result<Repository, GitError> {
+        log::info!("Pulling {}, branch {}", self.url, self.branch_name);
+        let changed = match self.pull(&repo) {
+            Ok(changed) => changed,
+            Err(GitError::HistoryRewritten) if self.on_rewrite == RewritePolicy::Reclone => {
+                log::warn!("{} was rewritten upstream, cloning it again", self.repo_name);
+                drop(repo);
+                std::fs::remove_dir_all(clone_path)
+                    .map_err(|e| GitError::FailedRepoClone(GitCause::io(&e, clone_path)))?;
+                return self.clone(clone_path);
+            }
+            Err(e) => return Err(e),
+        };
+        log::info!("{} paths changed in {}", changed.len(), self.repo_name);
+        if !self.sparse && !repo.is_bare() && sparse_set_path(&repo).exists() {
+            log::info!("Checking out the full tree of {}", self.repo_name);
+            repo.checkout_head(Some(CheckoutBuilder::new().force()))
+                .map_err(|e| GitError::CheckoutFailure(GitCause::new(&e, clone_path)))?;
+            let _ = std::fs::remove_file(sparse_set_path(&repo));
+        }
+        Ok(repo)
+    }
+
+    fn clone(&self, clone_path: &Path) -> Result<Repository, GitError> {
+        log::info!(
+            "Cloning {}, branch {}{}{}",
+            self.url,
+            self.branch_name,
+            self.depth.map(|depth| format!(", depth {depth}")).unwrap_or_default(),
+            if self.bare { ", bare" } else { "" }
+        );
+        let mut checkout = CheckoutBuilder::new();
+        if self.sparse {
+            // The sparse set is checked out once the caller knows which files it needs.
+            checkout.dry_run();
+        }
+        RepoBuilder::new()
+            .bare(self.bare)
+            .branch(self.branch_name.as_str())
+            .fetch_options(self.fetch_options(None))
+            .with_checkout(checkout)
+            .clone(self.url, clone_path)
+            .map_err(|e| GitError::FailedRepoClone(GitCause::new(&e, clone_path)))
+    }
+
+    /// Checkout options for `HEAD`, limited to the recorded sparse set in sparse mode.
+    fn checkout_options(&self, repo: &Repository) -> CheckoutBuilder<'static> {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        if self.sparse {
+            let paths = sparse_set(repo);
+            if paths.is_empty() {
+                checkout.dry_run();
+            }
+            for path in paths {
+                checkout.path(path);
+            }
+        }
+        checkout
+    }
+
+    /// Makes `paths` the sparse set, checking out new paths from `HEAD` and removing the ones
+    /// that were dropped from the working tree.
+    ///
+    /// # Errors
+    /// Fails if the checkout fails or the sparse set can't be recorded.
+    pub fn sparse_checkout(&self, repo: &Repository, paths: &[String]) -> Result<(), GitError> {
+        let previous = sparse_set(repo);
+        if !paths.is_empty() {
+            let mut checkout = CheckoutBuilder::new();
+            checkout.force();
+            for path in paths {
+                checkout.path(path.as_str());
+            }
+            repo.checkout_head(Some(&mut checkout))
+                .map_err(|e| GitError::CheckoutFailure(GitCause::new(&e, repo.path())))?;
+        }
+        let removed: Vec<&String> = previous.iter().filter(|p| !paths.contains(p)).collect();
+        if let Some(workdir) = repo.workdir() {
+            for path in &removed {
+                let _ = std::fs::remove_file(workdir.join(path));
+            }
+        }
+        std::fs::write(sparse_set_path(repo), paths.join("\n"))
+            .map_err(|e| GitError::CheckoutFailure(GitCause::io(&e, &sparse_set_path(repo))))?;
+        log::info!(
+            "Sparse checkout of {} has {} paths, {} added and {} removed",
+            self.repo_name,
+            paths.len(),
+            paths.iter().filter(|p| !previous.contains(p)).count(),
+            removed.len()
+        );
+        Ok(())
+    }
+}
+
+/// A point in a branch's history to read metadata at instead of its tip.
+///
+/// Written either as a revision (commit id, tag, ...) or as a UTC date or time like `2024-03-12`
+/// or `2024-03-12T18:00:00Z`, meaning the last commit made at or before it. A bare date means the
+/// end of that day, so it takes in every commit made on it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Snapshot {
+    Revision(String),
+    /// Seconds since the Unix epoch.
+    Before(i64),
+}
+impl std::str::FromStr for Snapshot {
+    type Err = String;
+    fn from_str(snapshot: &str) -> Result<Self, Self::Err> {
+        let snapshot = snapshot.trim();
+        if snapshot.is_empty() {
+            return Err("expected a revision or a date like 2024-03-12".to_string());
+        }
+        // Anything shaped like a date has to be a valid one, rather than silently becoming a
+        // revision that can't be found.
+        let looks_like_date = snapshot.len() >= 10
+            && snapshot.as_bytes()[4] == b'-'
+            && snapshot[..4].bytes().all(|b| b.is_ascii_digit());
+        if looks_like_date {
+            return parse_utc(snapshot)
+                .map(Self::Before)
+                .ok_or_else(|| format!("{snapshot:?} is not a date like 2024-03-12 or 2024-03-12T18:00:00Z"));
+        }
+        Ok(Self::Revision(snapshot.to_string()))
+    }
+}
+impl TryFrom<String> for Snapshot {
+    type Error = String;
+    fn try_from(snapshot: String) -> Result<Self, Self::Error> {
+        snapshot.parse()
+    }
+}
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Revision(revision) => write!(f, "{revision}"),
+            Self::Before(timestamp) => {
+                let (days, seconds) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+                let (year, month, day) = civil_from_days(days);
+                write!(f, "{year:04}-{month:02}-{day:02}")?;
+                if seconds != END_OF_DAY {
+                    write!(f, "T{:02}:{:02}:{:02}Z", seconds / 3600, seconds / 60 % 60, seconds % 60)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl From<Snapshot> for String {
+    fn from(snapshot: Snapshot) -> Self {
+        snapshot.to_string()
+    }
+}
+
+/// Seconds into the day of its last second, 23:59:59.
+const END_OF_DAY: i64 = 86399;
+
+/// Parses `YYYY-MM-DD`, optionally followed by `THH:MM[:SS]` and `Z`, as seconds since the epoch.
+/// A bare date is the last second of that day.
+fn parse_utc(text: &str) -> Option<i64> {
+    let text = text.strip_suffix('Z').unwrap_or(text);
+    let (date, time) = text.split_once(['T', ' ']).unwrap_or((text, "23:59:59"));
+    let number = |part: Option<&str>| part?.parse::<i64>().ok();
+    let mut date_parts = date.split('-');
+    let (year, month, day) = (number(date_parts.next())?, number(date_parts.next())?, number(date_parts.next())?);
+    let mut time_parts = time.split(':');
+    let (hour, minute) = (number(time_parts.next())?, number(time_parts.next())?);
+    let second = time_parts.next().map_or(Some(0), |second| number(Some(second)))?;
+    if date_parts.next().is_some() || time_parts.next().is_some() {
+        return None;
+    }
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    let valid = (1..=days_in_month).contains(&day)
+        && (0..24).contains(&hour)
+        && (0..60).contains(&minute)
+        && (0..60).contains(&second);
+    if !valid {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, after Howard Hinnant's `days_from_civil`.
+const fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The inverse of [`days_from_civil`].
+const fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Resolves `snapshot` to a commit reachable from `HEAD`, without moving any branch.
+///
+/// # Errors
+/// Returns `SnapshotNotFound` if the revision doesn't name a commit, or no commit in the local
+/// history is old enough, ie. because a shallow clone doesn't reach back that far.
+pub fn resolve_snapshot(repo: &Repository, snapshot: &Snapshot) -> Result<Oid, GitError> {
+    let not_found = |why: &str| GitError::SnapshotNotFound(format!("{snapshot}: {why}"));
+    match snapshot {
+        Snapshot::Revision(revision) => repo
+            .revparse_single(revision)
+            .and_then(|object| object.peel_to_commit())
+            .map(|commit| commit.id())
+            .map_err(|e| not_found(e.message())),
+        Snapshot::Before(timestamp) => {
+            let mut walk = repo.revwalk().map_err(|e| not_found(e.message()))?;
+            walk.push_head().map_err(|e| not_found(e.message()))?;
+            walk.set_sorting(git2::Sort::TIME).map_err(|e| not_found(e.message()))?;
+            walk.filter_map(Result::ok)
+                .find(|oid| {
+                    repo.find_commit(*oid)
+                        .is_ok_and(|commit| commit.committer().when().seconds() <= *timestamp)
+                })
+                .ok_or_else(|| not_found("no commit that old in the local history"))
+        }
+    }
+}
+
+/// Renames the clone at `clone_path` to `<name>.broken-<unix time>` next to it, returning the new
+/// path.
+fn move_aside(clone_path: &Path) -> Result<PathBuf, GitError> {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut aside = clone_path.as_os_str().to_owned();
+    aside.push(format!(".broken-{}", since_epoch.as_secs()));
+    let aside = PathBuf::from(aside);
+    std::fs::rename(clone_path, &aside)
+        .map_err(|e| GitError::LocalRepoIsBusted(GitCause::io(&e, clone_path)))?;
+    Ok(aside)
+}
+
+/// Lists the paths that differ between the trees of commits `from` and `to`.
+///
+/// # Errors
+/// Fails if either commit is missing, ie. because a shallow clone doesn't reach it.
+pub fn changed_paths(repo: &Repository, from: Oid, to: Oid) -> Result<Vec<String>, GitError> {
+    let tree = |oid| {
+        repo.find_commit(oid)
+            .and_then(|commit| commit.tree())
+            .map_err(|e| GitError::CouldNotFindReference(GitCause::new(&e, repo.path())))
+    };
+    let diff = repo
+        .diff_tree_to_tree(Some(&tree(from)?), Some(&tree(to)?), None)
+        .map_err(|e| GitError::CouldNotFindReference(GitCause::new(&e, repo.path())))?;
+    Ok(diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .filter_map(|path| path.to_str().map(str::to_string))
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect())
+}
+
+/// The commit `HEAD` points to in the repository at `repo_path`, if there is one.
+#[must_use]
+pub fn head_commit(repo_path: &Path) -> Option<Oid> {
+    let repo = Repository::open(repo_path).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id())
+}
+
+/// When `repo` was last cloned or pulled by [`GitHelper::clone_or_pull`].
+#[must_use]
+pub fn last_synced(repo: &Repository) -> Option<std::time::SystemTime> {
+    std::fs::metadata(repo.path().join(SYNCED_MARKER))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn sparse_set_path(repo: &Repository) -> PathBuf {
+    repo.path().join(SPARSE_SET)
+}
+
+/// The paths checked out by the last sparse checkout of `repo`, empty if it has never had one.
+#[must_use]
+pub fn sparse_set(repo: &Repository) -> Vec<String> {
+    std::fs::read_to_string(sparse_set_path(repo))
+        .map(|set| set.lines().filter(|l| !l.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Lists the names of the blobs directly within `dir` in the tree of `HEAD`, without needing
+/// them to be checked out.
+///
+/// # Errors
+/// Fails if `HEAD` can't be resolved, or `dir` is not a directory in its tree.
+pub fn list_head_dir(repo: &Repository, dir: &str) -> Result<Vec<String>, GitError> {
+    let head = repo
+        .head()
+        .and_then(|head| head.peel_to_commit())
+        .map_err(|e| GitError::CouldNotFindReference(GitCause::new(&e, repo.path())))?;
+    list_commit_dir(repo, head.id(), dir)
+}
+
+/// As [`list_head_dir`], in the tree of `commit`.
+///
+/// # Errors
+/// Fails if `commit` can't be found, or `dir` is not a directory in its tree.
+pub fn list_commit_dir(repo: &Repository, commit: Oid, dir: &str) -> Result<Vec<String>, GitError> {
+    let tree = repo
+        .find_commit(commit)
+        .and_then(|commit| commit.tree())
+        .map_err(|e| GitError::CouldNotFindReference(GitCause::new(&e, repo.path())))?;
+    let subtree = if dir.is_empty() {
+        tree
+    } else {
+        tree.get_path(Path::new(dir))
+            .and_then(|entry| entry.to_object(repo))
+            .and_then(|object| object.peel_to_tree())
+            .map_err(|_err| GitError::PathNotInTree(dir.to_string()))?
+    };
+    Ok(subtree
+        .iter()
+        .filter(|entry| entry.kind() == Some(ObjectType::Blob))
+        .filter_map(|entry| entry.name().map(str::to_string))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn before(snapshot: &str) -> i64 {
+        match snapshot.parse() {
+            Ok(Snapshot::Before(timestamp)) => timestamp,
+            other => panic!("{snapshot} parsed as {other:?}"),
+        }
+    }
+
+    #[test]
+    fn dates_parse_as_utc() {
+        assert_eq!(before("1970-01-01T00:00Z"), 0);
+        assert_eq!(before("1969-12-31T00:00Z"), -86_400);
+        assert_eq!(before("2000-03-01T00:00Z"), 951_868_800);
+        assert_eq!(before("2100-03-01T00:00Z"), 4_107_542_400);
+        assert_eq!(before(" 2024-03-12T00:00Z "), 1_710_201_600);
+    }
+
+    #[test]
+    fn bare_dates_are_the_end_of_the_day() {
+        assert_eq!(before("1970-01-01"), 86_399);
+        assert_eq!(before("1969-12-31"), -1);
+        assert_eq!(before(" 2024-03-12 "), 1_710_287_999);
+        assert_eq!(before("2024-03-12"), before("2024-03-12T23:59:59Z"));
+    }
+
+    #[test]
+    fn date_times_parse_with_and_without_z() {
+        assert_eq!(before("2024-03-12T18:30:05Z"), 1_710_268_205);
+        assert_eq!(before("2024-03-12T18:30:05"), 1_710_268_205);
+        assert_eq!(before("2024-03-12 18:30:05"), 1_710_268_205);
+        assert_eq!(before("2024-03-12T18:30Z"), 1_710_268_200);
+        assert_eq!(before("2024-03-12T18:30"), 1_710_268_200);
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(before("2024-02-29"), 1_709_251_199);
+        assert_eq!(before("2000-02-29"), 951_868_799);
+        assert!("2023-02-29".parse::<Snapshot>().is_err());
+        assert!("2100-02-29".parse::<Snapshot>().is_err());
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        for invalid in [
+            "2024-02-30",
+            "2024-04-31",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-03-00",
+            "2024-03-12T24:00",
+            "2024-03-12T18:60",
+            "2024-03-12T18:30:60Z",
+            "2024-03-12T18",
+            "2024-03-12T18:30:05:00",
+            "2024-03-1x",
+            "2024-03-12-01",
+        ] {
+            assert!(invalid.parse::<Snapshot>().is_err(), "{invalid} should be rejected");
+        }
+        assert!("".parse::<Snapshot>().is_err());
+    }
+
+    #[test]
+    fn other_values_are_revisions() {
+        for revision in ["HEAD~1", "gh-pages", "d32f409a70", "2024", "12345678-abc", "v2024-03-12"] {
+            assert_eq!(revision.parse(), Ok(Snapshot::Revision(revision.to_string())));
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for snapshot in [
+            "2024-02-29",
+            "1969-12-31",
+            "2024-03-12T18:30:05Z",
+            "2024-03-12T00:00:00Z",
+            "2024-03-12T23:59:58Z",
+            "HEAD~1",
+        ] {
+            let parsed: Snapshot = snapshot.parse().unwrap();
+            assert_eq!(parsed.to_string(), snapshot);
+            assert_eq!(parsed.to_string().parse(), Ok(parsed));
+        }
+        assert_eq!("2024-03-12T23:59:59Z".parse::<Snapshot>().unwrap().to_string(), "2024-03-12");
+    }
 
-            let r = repo.map_err(|_|GitError::LocalRepoIsBusted)?;
-            GitHelper::pull(&r, self.branch_name)
-                .map_err(|_err| GitError::FailedRepoClone)?;
-            Ok(r)
+    #[test]
+    fn civil_days_round_trip() {
+        for days in (-800_000..800_000).step_by(97) {
+            let (year, month, day) = civil_from_days(days);
+            assert!((1..=12).contains(&month) && (1..=31).contains(&day));
+            assert_eq!(days_from_civil(year, month, day), days);
         }
     }
 }
@@ -0,0 +1,42 @@
+//! Diffs Windows binarys based upon Winbindex metadata. Uses Ghidriff to power the diffs.
+//!
+//! The pipeline is split into independent pieces that can be used on their own:
+//!
+//! * load metadata: [`Winbindex::load_file`] or [`pipeline::load_file_data`]
+//! * resolve versions: [`WinbindexFileData::resolve`] and [`WinbindexFileData::find_previous_for_entry`]
+//! * select entries: [`WinbindexFileData::query`] with a [`winbindex_query::EntryQuery`]
+//! * group changes by update: [`update_index::UpdateIndex`]
+//! * download: [`ghidriff_utils::download_binary`]
+//! * diff: [`GhidriffDiffingProject`]
+//! * record progress: [`StorageProvider`] and [`progress::BinaryProgressStore`]
+//!
+//! [`DiffPlan`] ties these together the same way the `winbindex_differ` binary does.
+
+#![warn(
+    clippy::all,
+    clippy::pedantic,
+    clippy::nursery,
+    clippy::cargo,
+    clippy::unwrap_in_result
+)]
+
+pub mod config_layers;
+pub mod config_schema;
+pub mod config_validation;
+pub mod diff_config;
+pub mod ghidriff_utils;
+pub mod git_utils;
+pub mod pipeline;
+pub mod progress;
+#[cfg(test)]
+mod test_support;
+pub mod update_index;
+pub mod winbindex_query;
+pub mod winbindex_utils;
+
+pub use diff_config::ConfigFile;
+pub use ghidriff_utils::GhidriffDiffingProject;
+pub use git_utils::GitHelper;
+pub use pipeline::DiffPlan;
+pub use progress::StorageProvider;
+pub use winbindex_utils::{Arch, BinaryVersion, Winbindex, WinbindexEntry, WinbindexFileData};
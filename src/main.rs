@@ -1,4 +1,4 @@
-//! Diffs Windows binarys based upon Winbindex metadata. Uses Ghidriff to power the diffs.
+//! Command line frontend for the `winbindex_differ` library.
 //! Intended to be run in CI/CD to produce continuous diffs.
 
 #![warn(
@@ -9,108 +9,510 @@
     clippy::unwrap_in_result
 )]
 
-use progress::StorageProvider;
+use clap::Parser;
+use serde::Serialize;
+use cli::{Cli, Command, OutputFormat};
 use std::path::Path;
+use winbindex_differ::config_schema::CURRENT_VERSION;
+use winbindex_differ::diff_config::{Age, ConfigFileError};
+use winbindex_differ::git_utils::last_synced;
+use winbindex_differ::ghidriff_utils::GhidriffError;
+use winbindex_differ::pipeline::{self, PipelineError, PlanOptions};
+use winbindex_differ::update_index::{UpdateChange, UpdateIndex};
+use winbindex_differ::winbindex_query::EntryQuery;
+use winbindex_differ::winbindex_utils::{Anomaly, WinbindexError};
+use winbindex_differ::{
+    Arch, ConfigFile, DiffPlan, GhidriffDiffingProject, StorageProvider, WinbindexFileData,
+};
 extern crate tokio;
-use crate::{ghidriff_utils::GhidriffDiffingProject, winbindex_utils::{Arch, Winbindex}};
 
-mod diff_config;
-mod git_utils;
-mod progress;
-mod winbindex_utils;
-mod ghidriff_utils;
+mod cli;
+
+#[derive(Debug)]
+enum CliError {
+    Config(ConfigFileError),
+    Winbindex(WinbindexError),
+    Ghidriff(GhidriffError),
+    Pipeline(PipelineError),
+    ProgressStore,
+    Serialization(serde_json::Error),
+    YamlSerialization(serde_yaml::Error),
+    UnknownBranch(String),
+    UnknownArch(String),
+    ArchMismatch,
+}
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "config file: {e}"),
+            Self::Winbindex(e) => write!(f, "winbindex: {e}"),
+            Self::Ghidriff(e) => write!(f, "ghidriff: {e:?}"),
+            Self::Pipeline(e) => write!(f, "{e}"),
+            Self::ProgressStore => write!(f, "could not open the progress store"),
+            Self::Serialization(e) => write!(f, "could not serialize output: {e}"),
+            Self::YamlSerialization(e) => write!(f, "could not serialize output: {e}"),
+            Self::UnknownBranch(name) => write!(f, "branch {name} is not configured"),
+            Self::UnknownArch(name) => write!(f, "unknown architecture {name}"),
+            Self::ArchMismatch => write!(f, "both versions must have the same architecture"),
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let config_file_path = Path::new("../sample/config.yaml"); //argv[1]
+    let cli = Cli::parse();
+    env_logger::Builder::new()
+        .filter_level(cli.log_level())
+        .format_timestamp(None)
+        .init();
 
-    let config_file = diff_config::ConfigFile::open_or_create(config_file_path)
-        .expect("Could not open config file");
+    let result = match &cli.command {
+        Command::Init => init(&cli.config),
+        Command::Migrate => migrate(&cli.config),
+        command => load_and_dispatch(&cli, command).await,
+    };
 
-    let store_dir = Path::new(config_file.store_dir.as_str());
-    config_file.update_repos().unwrap();
-
-    // iterate through all provided Winbindex Git repositorys, this will be arm64, x64 and insider.
-    for (repo_name, repo) in &config_file.branches{
-        let instance = repo_name;
-        let mut progress_store = StorageProvider::new_or_create(store_dir).unwrap();
-        let progress = progress_store.get_or_create_branch_store(repo_name);
-        // iterate through all of the binarys for which  we wish to generate diffs
-        for binary_name in &repo.files{
-            let wb = Winbindex::new(Path::new(&config_file.repo_dir)
-                .join(repo_name).to_str().unwrap(), &repo.data_dir);
-            let file_data = wb.load_file(binary_name, repo_name).unwrap();
-            let json = &file_data.data;
-
-            // If this binary has not been seen before as per the progress storage file, diff all
-            // versions of it
-            if progress.none_indexed(binary_name){
-                let j = json.clone();
-
-                let amd64 = j.iter().filter_map(|( _k,  v)| (v.get_arch()?==Arch::Amd64 && v.get_download_url().is_some()).then_some(v.clone())).collect();
-                let arm64 = j.iter().filter_map(|( _k, v)| (v.get_arch()?==Arch::Arm64 && v.get_download_url().is_some()).then_some(v.clone())).collect();
-                let x86 = j.iter().filter_map(|( _k, v)| (v.get_arch()?==Arch::X86 && v.get_download_url().is_some()).then_some(v.clone())).collect();
-                
-                let gd_amd64 = GhidriffDiffingProject::new(Path::new(&config_file.store_dir).to_path_buf(), instance, binary_name,Arch::Amd64);
-                let gd_arm64 = GhidriffDiffingProject::new(Path::new(&config_file.store_dir).to_path_buf(), instance, binary_name,Arch::Arm64);
-                let gd_x86 = GhidriffDiffingProject::new(Path::new(&config_file.store_dir).to_path_buf(), instance, binary_name,Arch::X86);
-                
-                gd_amd64.run_diff_on_all(&amd64).await.unwrap();
-                gd_arm64.run_diff_on_all(& arm64).await.unwrap();
-                gd_x86.run_diff_on_all(& x86).await.unwrap();
-                for binary in &amd64{
-                    progress.add(binary_name, binary.get_sha256().unwrap().as_ref());
-                }
-                for binary in &arm64{
-                    progress.add(binary_name, binary.get_sha256().unwrap().as_ref());
-                }
-                for binary in &x86{
-                    progress.add(binary_name, binary.get_sha256().unwrap().as_ref());
+    if let Err(e) = result {
+        log::error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+/// Loads the config, applies command line overrides, and runs `command`.
+async fn load_and_dispatch(cli: &Cli, command: &Command) -> Result<(), CliError> {
+    match ConfigFile::load(&cli.config, cli.profile.as_deref()) {
+        Ok(mut config_file) => {
+            if let Some(store_dir) = &cli.store_dir {
+                config_file.store_dir = store_dir.to_string_lossy().to_string();
+            }
+            if let Some(repo_dir) = &cli.repo_dir {
+                config_file.repo_dir = repo_dir.to_string_lossy().to_string();
+            }
+            config_file.offline |= cli.offline;
+            config_file.max_repo_age = cli.max_repo_age.or(config_file.max_repo_age);
+            if let Some(at) = &cli.at {
+                for branch in config_file.branches.values_mut() {
+                    branch.at = Some(at.clone());
                 }
-        
             }
-            else{
-                
-                let next_entry = json.iter()
-                    .find(|&(k, _v)| !progress.is_in_index(binary_name, k));
-        
-        
-                if next_entry.is_some(){
-                    //1. Find previous for `v`
-                    //2. Run diff for `v` and `v-1`
-                    //3. update progresstore and flush
-                    let hash = next_entry.unwrap().0;
-                    let data = next_entry.unwrap().1;
-        
-        
-                    //[1]
-                    //Find previous
-                    let prev = file_data.find_previous_for_entry(data);
-                    
-                    //[2] run diff
-                    let gd = GhidriffDiffingProject::new(Path::new(&config_file.store_dir).to_path_buf(), instance, binary_name,data.get_arch().unwrap());
-                    let mut bins = Vec::new();
-                    if prev.is_none(){
-                        continue;
+            dispatch(command, &config_file).await
+        }
+        Err(e) => Err(CliError::Config(e)),
+    }
+}
+
+async fn dispatch(command: &Command, config_file: &ConfigFile) -> Result<(), CliError> {
+    match command {
+        Command::Init | Command::Migrate => unreachable!("handled before the config is loaded"),
+        Command::Validate => validate(config_file),
+        Command::Sync => {
+            check_config(config_file)?;
+            config_file.update_repos().map_err(CliError::Config)
+        }
+        Command::Run { full } => {
+            config_file.update_repos().map_err(CliError::Config)?;
+            check_config(config_file)?;
+            run(config_file, &PlanOptions { full: *full }).await
+        }
+        Command::Plan { format, full } => {
+            check_config(config_file)?;
+            plan(config_file, *format, &PlanOptions { full: *full })
+        }
+        Command::ShowConfig => show_config(config_file),
+        Command::Status => status(config_file),
+        Command::Files => files(config_file),
+        Command::ListVersions { binary, branch, arch } => {
+            list_versions(config_file, binary, branch.as_deref(), arch.as_deref())
+        }
+        Command::Anomalies { binary, branch, format } => {
+            anomalies(config_file, binary.as_deref(), branch.as_deref(), *format)
+        }
+        Command::Update { update, branch, format, diff } => {
+            changed_in_update(config_file, update, branch.as_deref(), *format, *diff).await
+        }
+        Command::Diff { branch, binary, old, new, arch } => {
+            diff(config_file, branch, binary, old, new, arch.as_deref()).await
+        }
+    }
+}
+
+/// Writes a starter config to `path`.
+fn init(path: &Path) -> Result<(), CliError> {
+    ConfigFile::init(path).map_err(CliError::Config)?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+/// Upgrades the config at `path` to the current layout version.
+fn migrate(path: &Path) -> Result<(), CliError> {
+    match ConfigFile::migrate_file(path).map_err(CliError::Config)? {
+        Some(backup) => println!(
+            "Migrated {} to version {CURRENT_VERSION}, the original is at {}",
+            path.display(),
+            backup.display()
+        ),
+        None => println!("{} is already at version {CURRENT_VERSION}", path.display()),
+    }
+    Ok(())
+}
+
+/// Logs config warnings, failing if the config has any errors.
+fn check_config(config_file: &ConfigFile) -> Result<(), CliError> {
+    let report = config_file.validate();
+    for warning in report.warnings() {
+        log::warn!("{warning}");
+    }
+    if report.has_errors() {
+        return Err(CliError::Config(ConfigFileError::Invalid(report.errors())));
+    }
+    Ok(())
+}
+
+/// Prints every problem found in the config.
+fn validate(config_file: &ConfigFile) -> Result<(), CliError> {
+    let report = config_file.validate();
+    for issue in &report.issues {
+        println!("{issue}");
+    }
+    if report.has_errors() {
+        return Err(CliError::Config(ConfigFileError::Invalid(report.errors())));
+    }
+    println!("Config is valid");
+    Ok(())
+}
+
+/// Loads the Winbindex metadata for `binary_name` from the local clone of `branch`.
+fn load_file_data(
+    config_file: &ConfigFile,
+    branch: &str,
+    binary_name: &str,
+) -> Result<WinbindexFileData, CliError> {
+    if !config_file.branches.contains_key(branch) {
+        return Err(CliError::UnknownBranch(branch.to_string()));
+    }
+    pipeline::load_file_data(config_file, branch, binary_name).map_err(CliError::Winbindex)
+}
+
+/// Diffs every version of the configured binaries which has not been diffed yet.
+async fn run(config_file: &ConfigFile, options: &PlanOptions) -> Result<(), CliError> {
+    let store_dir = Path::new(config_file.store_dir.as_str());
+    let mut progress_store =
+        StorageProvider::new_or_create(store_dir).ok_or(CliError::ProgressStore)?;
+    let plan = DiffPlan::for_config(config_file, Some(&progress_store), options)
+        .map_err(CliError::Pipeline)?;
+    plan.execute(store_dir, &mut progress_store)
+        .await
+        .map_err(CliError::Ghidriff)
+}
+
+/// Opens the progress store for reading, without creating one. A missing store just means nothing
+/// has been diffed yet, while one that can't be read is an error, as it would be for `run`.
+fn open_progress_store(config_file: &ConfigFile) -> Result<Option<StorageProvider>, CliError> {
+    let store_dir = Path::new(&config_file.store_dir);
+    let progress_store = StorageProvider::open(store_dir);
+    if progress_store.is_none() && store_dir.join("progress.yaml").exists() {
+        return Err(CliError::ProgressStore);
+    }
+    Ok(progress_store)
+}
+
+/// Prints the diffs `run` would perform.
+fn plan(config_file: &ConfigFile, format: OutputFormat, options: &PlanOptions) -> Result<(), CliError> {
+    let progress_store = open_progress_store(config_file)?;
+    let plan = DiffPlan::for_config(config_file, progress_store.as_ref(), options)
+        .map_err(CliError::Pipeline)?;
+    match format {
+        OutputFormat::Table => plan.print_table(),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&plan).map_err(CliError::Serialization)?
+        ),
+    }
+    Ok(())
+}
+
+/// Prints the fully merged config as YAML.
+fn show_config(config_file: &ConfigFile) -> Result<(), CliError> {
+    print!(
+        "{}",
+        serde_yaml::to_string(config_file).map_err(CliError::YamlSerialization)?
+    );
+    Ok(())
+}
+
+/// Prints the number of versions diffed for every configured branch and binary.
+fn status(config_file: &ConfigFile) -> Result<(), CliError> {
+    let progress_store = open_progress_store(config_file)?;
+    for (branch_name, branch) in &config_file.branches {
+        let synced_ago = git2::Repository::open(Path::new(&config_file.repo_dir).join(branch_name))
+            .ok()
+            .map(|repo| last_synced(&repo).and_then(|synced| synced.elapsed().ok()));
+        println!(
+            "{branch_name} ({}, branch {}){}",
+            branch.repo_url,
+            branch.branch,
+            match synced_ago {
+                None => " [not cloned]".to_string(),
+                Some(None) => String::new(),
+                Some(Some(ago)) => format!(" [synced {} ago]", Age::rounded(ago)),
+            }
+        );
+        if let Some(at) = &branch.at {
+            match config_file.snapshot_commit(branch_name) {
+                Ok(Some(commit)) => println!("    pinned at {at} ({commit})"),
+                Ok(None) => {}
+                Err(e) => println!("    pinned at {at}, which can't be resolved: {e}"),
+            }
+        }
+        let progress = progress_store.as_ref().and_then(|store| store.branch_store(branch_name));
+        if let Some(commit) = progress.and_then(|p| p.last_commit()) {
+            println!("    last processed commit {commit}");
+        }
+        let files = config_file
+            .resolve_files(branch_name)
+            .map_err(CliError::Config)?
+            .files;
+        for file in &files {
+            let binary_name = &file.name;
+            let hashes = progress
+                .and_then(|p| p.binaries().find(|(name, _)| *name == binary_name))
+                .map(|(_, hashes)| hashes);
+            let indexed = hashes.map_or(0, Vec::len);
+            // Where the most recently indexed version's metadata came from.
+            let source = progress
+                .zip(hashes.and_then(|hashes| hashes.last()))
+                .and_then(|(p, hash)| p.provenance(binary_name, hash))
+                .map(|provenance| {
+                    let commit = provenance.commit.as_deref().unwrap_or("unknown commit");
+                    if provenance.repo_url == branch.repo_url && provenance.branch == branch.branch {
+                        format!(", latest from {commit}")
+                    } else {
+                        format!(", latest from {} {} {commit}", provenance.repo_url, provenance.branch)
                     }
-                    bins.push(prev.unwrap());
-                    bins.push(data.clone());
-                    gd.run_diff_on_all(&bins).await.unwrap();
-        
-        
-                    //[3] add to progress store
-                    progress.add(binary_name, hash);
-        
+                })
+                .unwrap_or_default();
+            println!("    {binary_name:<24} {indexed} versions indexed{source}");
+        }
+    }
+    Ok(())
+}
+
+/// Prints the binaries tracked by every branch, along with how many binaries each pattern matched.
+fn files(config_file: &ConfigFile) -> Result<(), CliError> {
+    for branch_name in config_file.branches.keys() {
+        let resolved = config_file
+            .resolve_files(branch_name)
+            .map_err(CliError::Config)?;
+        println!("{branch_name}: {} binaries", resolved.files.len());
+        for pattern in &resolved.patterns {
+            println!("    pattern {:<28} {} matches", pattern.pattern, pattern.matched);
+        }
+        for file in &resolved.files {
+            println!("    {}", file.name);
+        }
+    }
+    Ok(())
+}
+
+/// The branch named `branch`, or every branch if it is `None`.
+fn selected_branches<'a>(config_file: &'a ConfigFile, branch: Option<&str>) -> Result<Vec<&'a String>, CliError> {
+    match branch {
+        Some(branch) => Ok(vec![config_file
+            .branches
+            .get_key_value(branch)
+            .ok_or_else(|| CliError::UnknownBranch(branch.to_string()))?
+            .0]),
+        None => Ok(config_file.branches.keys().collect()),
+    }
+}
+
+/// Entries skipped while loading the metadata of one binary.
+#[derive(Serialize)]
+struct BinaryAnomalies<'a> {
+    branch: &'a str,
+    binary: String,
+    anomalies: Vec<Anomaly>,
+}
+
+/// Prints the entries skipped while loading the metadata of `binary_name`, or of every tracked
+/// binary.
+fn anomalies(
+    config_file: &ConfigFile,
+    binary_name: Option<&str>,
+    branch: Option<&str>,
+    format: OutputFormat,
+) -> Result<(), CliError> {
+    let mut report = Vec::new();
+    for branch_name in selected_branches(config_file, branch)? {
+        let binaries: Vec<String> = match binary_name {
+            Some(binary_name) => vec![binary_name.to_string()],
+            None => config_file
+                .resolve_files(branch_name)
+                .map_err(CliError::Config)?
+                .files
+                .into_iter()
+                .map(|file| file.name)
+                .collect(),
+        };
+        for binary in binaries {
+            let file_data = load_file_data(config_file, branch_name, &binary)?;
+            report.push(BinaryAnomalies { branch: branch_name, binary, anomalies: file_data.anomalies });
+        }
+    }
+    match format {
+        OutputFormat::Table => {
+            for binary in &report {
+                println!("{} / {}: {} anomalies", binary.branch, binary.binary, binary.anomalies.len());
+                for anomaly in &binary.anomalies {
+                    println!("    {anomaly}");
                 }
-                
             }
         }
-        progress_store.flush();
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).map_err(CliError::Serialization)?
+        ),
+    }
+    Ok(())
+}
+
+/// The binaries of one branch that changed in an update.
+#[derive(Serialize)]
+struct BranchUpdate<'a> {
+    branch: &'a str,
+    changes: Vec<&'a UpdateChange>,
+}
 
+/// Prints the tracked binaries that changed in `update`, and diffs them if asked to.
+async fn changed_in_update(
+    config_file: &ConfigFile,
+    update: &str,
+    branch: Option<&str>,
+    format: OutputFormat,
+    diff: bool,
+) -> Result<(), CliError> {
+    let mut indexes = Vec::new();
+    for branch_name in selected_branches(config_file, branch)? {
+        indexes.push(UpdateIndex::for_branch(config_file, branch_name).map_err(CliError::Pipeline)?);
+    }
+    let report: Vec<BranchUpdate> = indexes
+        .iter()
+        .map(|index| BranchUpdate { branch: &index.branch, changes: index.changes(update) })
+        .collect();
+    match format {
+        OutputFormat::Table => {
+            for branch in &report {
+                println!("{}: {} changes in {update}", branch.branch, branch.changes.len());
+                for change in &branch.changes {
+                    let previous = change
+                        .previous
+                        .as_ref()
+                        .map_or_else(|| "(new)".to_string(), |previous| previous.version.clone());
+                    println!(
+                        "    {:<24} {:<6} {:<18} -> {:<18} {}",
+                        change.binary, change.arch, previous, change.new.version, change.new.sha256
+                    );
+                }
+            }
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).map_err(CliError::Serialization)?
+        ),
     }
+    if diff {
+        let store_dir = Path::new(config_file.store_dir.as_str());
+        for index in &indexes {
+            for binary in index.plan(update).binaries {
+                for skipped in &binary.skipped {
+                    log::info!(
+                        "Not diffing {} {} {} ({})",
+                        binary.binary,
+                        skipped.arch,
+                        skipped.version,
+                        skipped.describe_reason()
+                    );
+                }
+                binary.run(store_dir).await.map_err(CliError::Ghidriff)?;
+            }
+        }
+    }
+    Ok(())
+}
 
+/// Prints every version of `binary_name`, sorted by version.
+fn list_versions(
+    config_file: &ConfigFile,
+    binary_name: &str,
+    branch: Option<&str>,
+    arch: Option<&str>,
+) -> Result<(), CliError> {
+    let arch = arch.map(parse_arch).transpose()?;
+    for branch_name in selected_branches(config_file, branch)? {
+        let file_data = match load_file_data(config_file, branch_name, binary_name) {
+            Ok(file_data) => file_data,
+            Err(e) if branch.is_none() => {
+                log::debug!("Skipping {branch_name}: {e:?}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let query = arch.map_or_else(EntryQuery::new, |arch| EntryQuery::new().with_arch(arch));
+        for entry in file_data.query(&query) {
+            let entry_arch: String = entry.get_arch().unwrap_or(Arch::Invalid).into();
+            println!(
+                "{branch_name:<10} {:<22} {entry_arch:<8} {} {:<12} {}",
+                entry.get_version().to_string(),
+                entry.get_sha256().unwrap_or_default(),
+                entry.get_kbs().join(","),
+                if entry.get_download_url().is_some() { "" } else { "(no download url)" }
+            );
+        }
+    }
+    Ok(())
+}
 
+/// Diffs two specific versions of a binary without touching the progress store.
+async fn diff(
+    config_file: &ConfigFile,
+    branch: &str,
+    binary_name: &str,
+    old: &str,
+    new: &str,
+    arch: Option<&str>,
+) -> Result<(), CliError> {
+    let arch = arch.map(parse_arch).transpose()?;
+    let file_data = load_file_data(config_file, branch, binary_name)?;
+    let old = file_data.resolve(old, arch).map_err(CliError::Winbindex)?;
+    let new = file_data.resolve(new, arch).map_err(CliError::Winbindex)?;
+    log::info!(
+        "Diffing {binary_name} {} ({}) against {} ({})",
+        old.get_version(),
+        old.get_sha256().unwrap_or_default(),
+        new.get_version(),
+        new.get_sha256().unwrap_or_default()
+    );
+    let arch = old.get_arch().unwrap_or(Arch::Invalid);
+    if new.get_arch() != Some(arch) {
+        return Err(CliError::ArchMismatch);
+    }
+    let file = config_file
+        .resolve_files(branch)
+        .ok()
+        .and_then(|resolved| resolved.files.into_iter().find(|f| f.name == binary_name));
+    let gd = GhidriffDiffingProject::new(
+        Path::new(&config_file.store_dir).to_path_buf(),
+        branch,
+        binary_name,
+        arch,
+    )
+    .with_options(config_file.ghidriff_options(branch, file.as_ref()));
+    gd.run_diff_on_all(&[old, new]).await.map_err(CliError::Ghidriff)
+}
 
-   
-    
+fn parse_arch(arch: &str) -> Result<Arch, CliError> {
+    match Arch::from(arch) {
+        Arch::Invalid => Err(CliError::UnknownArch(arch.to_string())),
+        arch => Ok(arch),
+    }
 }
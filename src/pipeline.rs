@@ -0,0 +1,654 @@
+//! Works out which diffs need to be run for the configured binaries, and runs them.
+//! Planning never downloads anything or invokes Ghidriff, so a plan can be inspected before
+//! committing to a long run.
+
+use crate::diff_config::{BranchConfig, ConfigFile, ConfigFileError, FileConfig};
+use crate::ghidriff_utils::{GhidriffDiffingProject, GhidriffError, GhidriffOptions};
+use crate::progress::{BinaryProgressStore, StorageProvider};
+use crate::winbindex_utils::{
+    Arch, Provenance, Winbindex, WinbindexEntry, WinbindexError, WinbindexFileData,
+};
+use crate::git_utils::{changed_paths, head_commit};
+use crate::winbindex_query::EntryQuery;
+use git2::{Oid, Repository};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum PipelineError {
+    Config(ConfigFileError),
+    Winbindex(WinbindexError),
+}
+impl std::fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "config file: {e}"),
+            Self::Winbindex(e) => write!(f, "winbindex: {e}"),
+        }
+    }
+}
+
+/// Architectures that are diffed when a binary is seen for the first time.
+const INITIAL_ARCHES: [Arch; 3] = [Arch::Amd64, Arch::Arm64, Arch::X86];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    NoDownloadUrl,
+    AlreadyIndexed,
+    NoPreviousVersion,
+    /// The version it replaced can't be downloaded, see `SkippedEntry::previous_sha256`.
+    PreviousNotDownloadable,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PlannedEntry {
+    pub sha256: String,
+    pub version: String,
+    #[serde(skip)]
+    pub entry: WinbindexEntry,
+}
+impl From<&WinbindexEntry> for PlannedEntry {
+    fn from(entry: &WinbindexEntry) -> Self {
+        Self {
+            sha256: entry.get_sha256().unwrap_or_default(),
+            version: entry.get_version().to_string(),
+            entry: entry.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct PlannedDiff {
+    pub arch: String,
+    pub old: PlannedEntry,
+    pub new: PlannedEntry,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SkippedEntry {
+    pub arch: String,
+    pub sha256: String,
+    pub version: String,
+    pub reason: SkipReason,
+    /// The entry at fault for `PreviousNotDownloadable`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_version: Option<String>,
+}
+impl SkippedEntry {
+    /// The reason as printed for humans, naming the previous entry when it is the one at fault.
+    #[must_use]
+    pub fn describe_reason(&self) -> String {
+        match (&self.previous_version, &self.previous_sha256) {
+            (Some(version), Some(sha256)) => format!("{:?}: {version} {sha256}", self.reason),
+            _ => format!("{:?}", self.reason),
+        }
+    }
+}
+
+/// The work planned for a single binary on a single branch.
+#[derive(Serialize, Clone)]
+pub struct BinaryPlan {
+    pub branch: String,
+    pub binary: String,
+    pub diffs: Vec<PlannedDiff>,
+    pub skipped: Vec<SkippedEntry>,
+    /// sha256s added to the progress store once the diffs have run.
+    pub record: Vec<String>,
+    /// Effective Ghidriff options for this binary.
+    #[serde(skip_serializing_if = "GhidriffOptions::is_empty")]
+    pub ghidriff: GhidriffOptions,
+    /// Where the metadata for this binary was loaded from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// `FileConfig::selector_hash` recorded to the progress store once the diffs have run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selector_hash: Option<String>,
+}
+
+/// Where a branch's metadata was planned from, and what could be skipped because of it.
+#[derive(Serialize, Debug, Clone)]
+pub struct BranchState {
+    pub branch: String,
+    /// Commit the metadata was loaded from, recorded as processed once the plan has run.
+    pub commit: Option<String>,
+    /// Commit processed by the previous run, if only changes since then were considered.
+    pub since: Option<String>,
+    /// Binaries whose metadata is unchanged since `since`, and so were not reloaded.
+    pub unchanged: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct DiffPlan {
+    pub branches: Vec<BranchState>,
+    pub binaries: Vec<BinaryPlan>,
+}
+
+/// Knobs for [`DiffPlan::for_config`].
+#[derive(Debug, Clone, Default)]
+pub struct PlanOptions {
+    /// Re-evaluate every binary, rather than only those whose metadata changed since the last
+    /// processed commit.
+    pub full: bool,
+}
+
+/// Loads the Winbindex metadata for `binary_name` from the local clone of `branch_name`.
+///
+/// Metadata is read at the branch's `at:` snapshot if it has one, and every entry is tagged with
+/// the upstream branch, repo URL and commit it was read from.
+///
+/// # Errors
+/// Fails if the snapshot can't be resolved, see also [`Winbindex::load_file`].
+pub fn load_file_data(
+    config_file: &ConfigFile,
+    branch_name: &str,
+    binary_name: &str,
+) -> Result<WinbindexFileData, WinbindexError> {
+    let commit = config_file
+        .snapshot_commit(branch_name)
+        .map_err(|e| WinbindexError::GitObject(e.to_string()))?;
+    load_file_data_at(config_file, branch_name, binary_name, commit)
+}
+
+/// As [`load_file_data`], reading metadata at `commit` if the branch reads from git and it is set.
+/// The local branch and working tree are left alone.
+///
+/// # Errors
+/// See [`Winbindex::load_file`].
+pub fn load_file_data_at(
+    config_file: &ConfigFile,
+    branch_name: &str,
+    binary_name: &str,
+    commit: Option<Oid>,
+) -> Result<WinbindexFileData, WinbindexError> {
+    let branch = config_file.branches.get(branch_name);
+    let data_dir = branch.map(BranchConfig::git_data_dir).unwrap_or_default();
+    let repo_path = Path::new(&config_file.repo_dir).join(branch_name);
+    let mut wb = Winbindex::new(repo_path.to_str().ok_or(WinbindexError::InvalidOsString)?, &data_dir);
+    if branch.is_some_and(BranchConfig::reads_from_git) {
+        wb = wb.at_revision(&commit.map_or_else(|| "HEAD".to_string(), |commit| commit.to_string()));
+    }
+    let mut file_data = wb.load_file(binary_name, branch_name)?;
+    if let Some(branch) = branch {
+        file_data.set_provenance(&Provenance {
+            branch: branch.branch.clone(),
+            repo_url: branch.repo_url.clone(),
+            commit: commit.or_else(|| head_commit(&repo_path)).map(|oid| oid.to_string()),
+        });
+    }
+    Ok(file_data)
+}
+
+/// Paths changed between the last processed commit and `head`, or `None` if that can't be
+/// worked out, ie. because the commit is no longer in a shallow or rewritten history.
+fn changed_since(repo_path: &Path, last: &str, head: Oid) -> Option<Vec<String>> {
+    let repo = Repository::open(repo_path).ok()?;
+    let last = Oid::from_str(last).ok()?;
+    match changed_paths(&repo, last, head) {
+        Ok(changed) => Some(changed),
+        Err(e) => {
+            log::info!("Re-evaluating every binary in {}, {last} is unavailable: {e}", repo_path.display());
+            None
+        }
+    }
+}
+
+pub(crate) fn arch_name(entry: &WinbindexEntry) -> String {
+    entry.get_arch().unwrap_or(Arch::Invalid).into()
+}
+
+pub(crate) fn skip(entry: &WinbindexEntry, reason: SkipReason) -> SkippedEntry {
+    SkippedEntry {
+        arch: arch_name(entry),
+        sha256: entry.get_sha256().unwrap_or_default(),
+        version: entry.get_version().to_string(),
+        reason,
+        previous_sha256: None,
+        previous_version: None,
+    }
+}
+
+impl BinaryPlan {
+    /// Plans the diffs for one binary given its metadata and the progress recorded so far.
+    /// Entries rejected by the file's architecture and version filters are ignored entirely.
+    ///
+    /// A binary that has never been seen is diffed version by version for every architecture.
+    /// Otherwise every version missing from the progress store is diffed against its nearest
+    /// downloadable predecessor.
+    #[must_use]
+    pub fn new(
+        branch_name: &str,
+        file: &FileConfig,
+        file_data: &WinbindexFileData,
+        progress: &BinaryProgressStore,
+    ) -> Self {
+        let binary_name = file.name.as_str();
+        let provenance = file_data.provenance().cloned();
+        let file_data = &file.filter(file_data);
+        let mut plan = Self {
+            branch: branch_name.to_string(),
+            binary: binary_name.to_string(),
+            diffs: Vec::new(),
+            skipped: Vec::new(),
+            record: Vec::new(),
+            ghidriff: file.ghidriff.clone(),
+            provenance,
+            selector_hash: Some(file.selector_hash()),
+        };
+        let entries = file_data.query(&EntryQuery::new());
+
+        if progress.none_indexed(binary_name) {
+            for arch in INITIAL_ARCHES {
+                let mut downloadable = Vec::new();
+                for entry in file_data.query(&EntryQuery::new().with_arch(arch)) {
+                    if entry.get_download_url().is_some() {
+                        downloadable.push(entry);
+                    } else {
+                        plan.skipped.push(skip(entry, SkipReason::NoDownloadUrl));
+                    }
+                }
+                plan.diffs.extend(downloadable.windows(2).map(|chunk| PlannedDiff {
+                    arch: arch.into(),
+                    old: chunk[0].into(),
+                    new: chunk[1].into(),
+                }));
+                plan.record
+                    .extend(downloadable.iter().filter_map(|v| v.get_sha256()));
+            }
+            return plan;
+        }
+
+        let predecessors = file_data.predecessors(true);
+        for entry in entries {
+            let Some(sha256) = entry.get_sha256() else {
+                continue;
+            };
+            if progress.is_in_index(binary_name, &sha256) {
+                plan.skipped.push(skip(entry, SkipReason::AlreadyIndexed));
+            } else if entry.get_download_url().is_none() {
+                plan.skipped.push(skip(entry, SkipReason::NoDownloadUrl));
+            } else if let Some(&prev) = predecessors.get(&sha256) {
+                plan.diffs.push(PlannedDiff {
+                    arch: arch_name(entry),
+                    old: prev.into(),
+                    new: entry.into(),
+                });
+                plan.record.push(sha256);
+            } else {
+                plan.skipped.push(skip(entry, SkipReason::NoPreviousVersion));
+            }
+        }
+        plan
+    }
+
+    /// Runs the planned diffs, one Ghidriff project per architecture, without recording progress.
+    ///
+    /// # Errors
+    /// Returns the first `GhidriffError` encountered.
+    pub async fn run(&self, store_dir: &Path) -> Result<(), GhidriffError> {
+        let mut arches: Vec<&String> = Vec::new();
+        for diff in &self.diffs {
+            if !arches.contains(&&diff.arch) {
+                arches.push(&diff.arch);
+            }
+        }
+        for arch in arches {
+            let pairs: Vec<_> = self
+                .diffs
+                .iter()
+                .filter(|d| &d.arch == arch)
+                .map(|d| (d.old.entry.clone(), d.new.entry.clone()))
+                .collect();
+            let gd = GhidriffDiffingProject::new(
+                store_dir.to_path_buf(),
+                &self.branch,
+                &self.binary,
+                Arch::from(arch.as_str()),
+            )
+            .with_options(self.ghidriff.clone());
+            gd.run_diff_on_pairs(&pairs).await?;
+        }
+        Ok(())
+    }
+}
+
+impl DiffPlan {
+    /// Plans the diffs for every configured branch and binary. Nothing is written to disk.
+    ///
+    /// Unless `options.full` is set, binaries that have been processed before and whose metadata
+    /// did not change since the last processed commit of their branch are not reloaded.
+    ///
+    /// # Errors
+    /// Fails if a branch has not been cloned, or its files can't be resolved or their metadata
+    /// can't be loaded.
+    pub fn for_config(
+        config_file: &ConfigFile,
+        progress_store: Option<&StorageProvider>,
+        options: &PlanOptions,
+    ) -> Result<Self, PipelineError> {
+        let mut plan = Self::default();
+        let empty = BinaryProgressStore::new();
+        for (branch_name, branch) in &config_file.branches {
+            let progress = progress_store
+                .and_then(|store| store.branch_store(branch_name))
+                .unwrap_or(&empty);
+            // Plans never sync, so without a clone there is no metadata to plan from.
+            config_file
+                .require_clone(branch_name)
+                .map_err(PipelineError::Config)?;
+            let resolved = config_file
+                .resolve_files(branch_name)
+                .map_err(PipelineError::Config)?;
+            let repo_path = Path::new(&config_file.repo_dir).join(branch_name);
+            let snapshot = config_file
+                .snapshot_commit(branch_name)
+                .map_err(PipelineError::Config)?;
+            if let (Some(commit), Some(at)) = (snapshot, &branch.at) {
+                log::info!("{branch_name}: reading metadata at {commit} ({at})");
+            }
+            let head = snapshot.or_else(|| head_commit(&repo_path));
+            let mut state = BranchState {
+                branch: branch_name.clone(),
+                commit: head.map(|oid| oid.to_string()),
+                since: None,
+                unchanged: Vec::new(),
+            };
+            let changed = match (progress.last_commit(), head) {
+                (Some(last), Some(head)) if !options.full => changed_since(&repo_path, last, head),
+                _ => None,
+            };
+            if changed.is_some() {
+                state.since = progress.last_commit().map(str::to_string);
+            }
+            for file in &resolved.files {
+                let metadata_path = branch.metadata_path(&file.name);
+                // Changed filters can select entries that were passed over before.
+                let selector_hash = file.selector_hash();
+                let selector_changed =
+                    progress.selector_hash(&file.name) != Some(selector_hash.as_str());
+                if changed.as_ref().is_some_and(|changed| !changed.contains(&metadata_path))
+                    && !progress.none_indexed(&file.name)
+                {
+                    if !selector_changed {
+                        state.unchanged.push(file.name.clone());
+                        continue;
+                    }
+                    let why = if progress.selector_hash(&file.name).is_some() {
+                        "its filters changed"
+                    } else {
+                        "its filters were not recorded"
+                    };
+                    log::info!("{branch_name} / {}: re-evaluating, {why}", file.name);
+                }
+                let file_data = load_file_data_at(config_file, branch_name, &file.name, head)
+                    .map_err(PipelineError::Winbindex)?;
+                let file = FileConfig {
+                    ghidriff: config_file.ghidriff_options(branch_name, Some(file)),
+                    ..file.clone()
+                };
+                plan.binaries.push(BinaryPlan::new(branch_name, &file, &file_data, progress));
+            }
+            if !state.unchanged.is_empty() {
+                log::info!(
+                    "{branch_name}: {} binaries unchanged since {}",
+                    state.unchanged.len(),
+                    state.since.as_deref().unwrap_or_default()
+                );
+            }
+            plan.branches.push(state);
+        }
+        Ok(plan)
+    }
+
+    /// Prints the plan as a human readable table.
+    pub fn print_table(&self) {
+        for branch in self.branches.iter().filter(|b| !b.unchanged.is_empty()) {
+            println!(
+                "{}: {} binaries unchanged since {}",
+                branch.branch,
+                branch.unchanged.len(),
+                branch.since.as_deref().unwrap_or_default()
+            );
+        }
+        for binary in &self.binaries {
+            println!(
+                "{} / {}: {} diffs, {} skipped",
+                binary.branch,
+                binary.binary,
+                binary.diffs.len(),
+                binary.skipped.len()
+            );
+            for diff in &binary.diffs {
+                println!(
+                    "    diff  {:<6} {:<18} {} -> {:<18} {}",
+                    diff.arch, diff.old.version, diff.old.sha256, diff.new.version, diff.new.sha256
+                );
+            }
+            for skipped in &binary.skipped {
+                println!(
+                    "    skip  {:<6} {:<18} {} ({})",
+                    skipped.arch,
+                    skipped.version,
+                    skipped.sha256,
+                    skipped.describe_reason()
+                );
+            }
+        }
+    }
+
+    /// Runs every planned diff, recording progress after each binary completes.
+    ///
+    /// # Errors
+    /// Returns the first `GhidriffError` encountered. Progress for completed binaries is kept.
+    pub async fn execute(
+        &self,
+        store_dir: &Path,
+        progress_store: &mut StorageProvider,
+    ) -> Result<(), GhidriffError> {
+        for binary in &self.binaries {
+            binary.run(store_dir).await?;
+            let progress = progress_store.get_or_create_branch_store(&binary.branch);
+            for sha256 in &binary.record {
+                progress.add(&binary.binary, sha256);
+                if let Some(provenance) = &binary.provenance {
+                    progress.set_provenance(&binary.binary, sha256, provenance);
+                }
+            }
+            if let Some(hash) = &binary.selector_hash {
+                progress.set_selector_hash(&binary.binary, hash);
+            }
+            if progress_store.flush().is_none() {
+                log::warn!("Could not write progress for {} / {}", binary.branch, binary.binary);
+            }
+        }
+        for branch in &self.branches {
+            if let Some(commit) = &branch.commit {
+                progress_store
+                    .get_or_create_branch_store(&branch.branch)
+                    .set_last_commit(commit);
+            }
+        }
+        if progress_store.flush().is_none() {
+            log::warn!("Could not write the processed commits");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        entry_json, file_data, sha256, simple_entry, TempDir, AMD64, ARM, ARM64, BINARY,
+    };
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn file() -> FileConfig {
+        FileConfig { name: BINARY.to_string(), ..FileConfig::default() }
+    }
+
+    fn diffs(plan: &BinaryPlan) -> Vec<(String, String, String)> {
+        let mut diffs: Vec<_> = plan
+            .diffs
+            .iter()
+            .map(|d| (d.arch.clone(), d.old.version.clone(), d.new.version.clone()))
+            .collect();
+        diffs.sort();
+        diffs
+    }
+
+    fn skipped(plan: &BinaryPlan) -> Vec<(String, SkipReason)> {
+        let mut skipped: Vec<_> = plan.skipped.iter().map(|s| (s.version.clone(), s.reason)).collect();
+        skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        skipped
+    }
+
+    fn diff(arch: &str, old: &str, new: &str) -> (String, String, String) {
+        (arch.to_string(), old.to_string(), new.to_string())
+    }
+
+    #[test]
+    fn first_run_diffs_every_initial_arch() {
+        let data = file_data([
+            simple_entry("10.0.1.1", AMD64, true),
+            simple_entry("10.0.1.2", AMD64, false),
+            simple_entry("10.0.1.3", AMD64, true),
+            simple_entry("10.0.1.4", ARM64, true),
+            simple_entry("10.0.1.5", ARM64, true),
+            simple_entry("10.0.1.6", ARM, true),
+        ]);
+        let plan = BinaryPlan::new("main", &file(), &data, &BinaryProgressStore::new());
+        assert_eq!(
+            diffs(&plan),
+            [diff("amd64", "10.0.1.1", "10.0.1.3"), diff("arm64", "10.0.1.4", "10.0.1.5")]
+        );
+        assert_eq!(skipped(&plan), [("10.0.1.2".to_string(), SkipReason::NoDownloadUrl)]);
+        let mut record = plan.record;
+        record.sort();
+        assert_eq!(
+            record,
+            [
+                sha256("10.0.1.1", AMD64),
+                sha256("10.0.1.3", AMD64),
+                sha256("10.0.1.4", ARM64),
+                sha256("10.0.1.5", ARM64),
+            ]
+        );
+    }
+
+    #[test]
+    fn incremental_run_diffs_new_versions_against_predecessors() {
+        let data = file_data([
+            simple_entry("10.0.1.1", AMD64, true),
+            simple_entry("10.0.1.2", AMD64, false),
+            simple_entry("10.0.1.3", AMD64, true),
+            simple_entry("10.0.1.4", AMD64, true),
+            simple_entry("10.0.1.5", ARM64, true),
+        ]);
+        let mut progress = BinaryProgressStore::new();
+        progress.add(BINARY, &sha256("10.0.1.1", AMD64));
+        let plan = BinaryPlan::new("main", &file(), &data, &progress);
+        // 10.0.1.2 can't be downloaded, so 10.0.1.3 is diffed against 10.0.1.1.
+        assert_eq!(
+            diffs(&plan),
+            [diff("amd64", "10.0.1.1", "10.0.1.3"), diff("amd64", "10.0.1.3", "10.0.1.4")]
+        );
+        assert_eq!(
+            skipped(&plan),
+            [
+                ("10.0.1.1".to_string(), SkipReason::AlreadyIndexed),
+                ("10.0.1.2".to_string(), SkipReason::NoDownloadUrl),
+                ("10.0.1.5".to_string(), SkipReason::NoPreviousVersion),
+            ]
+        );
+        assert_eq!(plan.record, [sha256("10.0.1.3", AMD64), sha256("10.0.1.4", AMD64)]);
+    }
+
+    #[test]
+    fn filtered_entries_are_ignored() {
+        let data = file_data([
+            simple_entry("10.0.1.1", AMD64, true),
+            simple_entry("10.0.1.2", AMD64, true),
+            simple_entry("10.0.1.3", ARM64, true),
+            simple_entry("10.0.1.4", ARM64, true),
+        ]);
+        let file = FileConfig { arches: vec![Arch::Arm64], ..file() };
+        let plan = BinaryPlan::new("main", &file, &data, &BinaryProgressStore::new());
+        assert_eq!(diffs(&plan), [diff("arm64", "10.0.1.3", "10.0.1.4")]);
+        assert!(plan.skipped.is_empty());
+        assert_eq!(plan.selector_hash, Some(file.selector_hash()));
+    }
+
+    /// Commits every file of the working tree of `repo`.
+    fn commit_all(repo: &Repository, message: &str) -> Oid {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn changed_filters_re_evaluate_unchanged_metadata() {
+        let dir = TempDir::new("changed_filters");
+        let repo_path = dir.path().join("repos").join("main");
+        std::fs::create_dir_all(repo_path.join("data")).unwrap();
+        let versions = [("10.0.1.1", AMD64), ("10.0.1.2", ARM64), ("10.0.1.3", ARM64)];
+        let entries: serde_json::Map<_, _> = versions
+            .into_iter()
+            .map(|(version, machine)| {
+                let kb = format!("KB{}", &version[7..]);
+                (sha256(version, machine), entry_json(version, machine, &[(&kb, version, 1)], true))
+            })
+            .collect();
+        let mut metadata = GzEncoder::new(Vec::new(), Compression::default());
+        metadata.write_all(serde_json::Value::Object(entries).to_string().as_bytes()).unwrap();
+        let metadata_path = repo_path.join("data").join("test.dll.json.gz");
+        std::fs::write(metadata_path, metadata.finish().unwrap()).unwrap();
+        let repo = Repository::init(&repo_path).unwrap();
+        let processed = commit_all(&repo, "metadata");
+        std::fs::write(repo_path.join("README.md"), "unrelated").unwrap();
+        commit_all(&repo, "unrelated");
+
+        // The binary was last evaluated for amd64 only, and its metadata did not change since.
+        let amd64_only = FileConfig { arches: vec![Arch::Amd64], ..file() };
+        let mut store = StorageProvider::new_or_create(&dir.path().join("store")).unwrap();
+        let progress = store.get_or_create_branch_store("main");
+        progress.add(BINARY, &sha256("10.0.1.1", AMD64));
+        progress.set_last_commit(&processed.to_string());
+        progress.set_selector_hash(BINARY, &amd64_only.selector_hash());
+
+        let plan_for = |arches: &str| {
+            let source = [
+                "version: 2".to_string(),
+                format!("store_dir: {}", dir.path().join("store").display()),
+                format!("repo_dir: {}", dir.path().join("repos").display()),
+                "branches:".to_string(),
+                "  main:".to_string(),
+                "    repo_url: u".to_string(),
+                "    branch: b".to_string(),
+                "    data_dir: data".to_string(),
+                format!("    files: [{{name: {BINARY}, arches: [{arches}]}}]"),
+            ]
+            .join("\n");
+            let config_file = ConfigFile::parse(&source, "test").unwrap();
+            DiffPlan::for_config(&config_file, Some(&store), &PlanOptions::default()).unwrap()
+        };
+        let plan = plan_for("amd64");
+        assert_eq!(plan.branches[0].unchanged, [BINARY]);
+        assert!(plan.binaries.is_empty());
+
+        let plan = plan_for("amd64, arm64");
+        assert!(plan.branches[0].unchanged.is_empty());
+        let [binary] = plan.binaries.as_slice() else { panic!("expected one binary") };
+        assert_eq!(diffs(binary), [diff("arm64", "10.0.1.2", "10.0.1.3")]);
+        assert_ne!(binary.selector_hash, Some(amd64_only.selector_hash()));
+    }
+}
\ No newline at end of file
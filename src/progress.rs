@@ -1,5 +1,6 @@
 //! Manages progress across multiple runs of the program. This is helpful for CI/CD scenarios.
 
+use crate::winbindex_utils::Provenance;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
@@ -8,11 +9,30 @@ use std::path::Path;
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BinaryProgressStore {
     binarys_indexed: HashMap<String, Vec<String>>, // binary_name : [hash1, hash2]
+    /// Commit of the branch's repository whose metadata was last fully processed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_commit: Option<String>,
+    /// Where the metadata of each indexed hash was loaded from, keyed by binary name then hash.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    provenance: HashMap<String, HashMap<String, Provenance>>,
+    /// `FileConfig::selector_hash` of each binary when it was last evaluated.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    selectors: HashMap<String, String>,
 }
+impl Default for BinaryProgressStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BinaryProgressStore {
+    #[must_use]
     pub fn new() -> Self {
         Self {
             binarys_indexed: HashMap::new(),
+            last_commit: None,
+            provenance: HashMap::new(),
+            selectors: HashMap::new(),
         }
     }
     /// Add an entry to the store.
@@ -21,13 +41,48 @@ impl BinaryProgressStore {
         list.push(hash.to_string());
     }
     /// Checks if a binary+hash combo exists in the store.
-    pub fn is_in_index(&mut self, filename:&str, hash: &str)->bool{
-        let list = self.binarys_indexed.entry(filename.to_string()).or_default();
-        list.contains(&hash.to_string())
+    #[must_use]
+    pub fn is_in_index(&self, filename:&str, hash: &str)->bool{
+        self.binarys_indexed.get(filename).is_some_and(|list| list.contains(&hash.to_string()))
     }
     /// Checks if there is no entry for a given binary.
+    #[must_use]
     pub fn none_indexed(&self, filename:&str) -> bool{
-        self.binarys_indexed.get(&filename.to_string()).is_none()
+        !self.binarys_indexed.contains_key(filename)
+    }
+    /// The commit whose metadata was last fully processed, if any.
+    #[must_use]
+    pub fn last_commit(&self) -> Option<&str> {
+        self.last_commit.as_deref()
+    }
+    /// Records the commit whose metadata has been fully processed.
+    pub fn set_last_commit(&mut self, commit: &str) {
+        self.last_commit = Some(commit.to_string());
+    }
+    /// Records where the metadata of an indexed binary+hash combo was loaded from.
+    pub fn set_provenance(&mut self, filename: &str, hash: &str, provenance: &Provenance) {
+        self.provenance
+            .entry(filename.to_string())
+            .or_default()
+            .insert(hash.to_string(), provenance.clone());
+    }
+    /// Where the metadata of a binary+hash combo was loaded from, if it was recorded.
+    #[must_use]
+    pub fn provenance(&self, filename: &str, hash: &str) -> Option<&Provenance> {
+        self.provenance.get(filename)?.get(hash)
+    }
+    /// The selector hash a binary was last evaluated with, if it was recorded.
+    #[must_use]
+    pub fn selector_hash(&self, filename: &str) -> Option<&str> {
+        self.selectors.get(filename).map(String::as_str)
+    }
+    /// Records the selector hash a binary has been evaluated with.
+    pub fn set_selector_hash(&mut self, filename: &str, hash: &str) {
+        self.selectors.insert(filename.to_string(), hash.to_string());
+    }
+    /// Iterates over all binaries in the store, along with the hashes that have been indexed.
+    pub fn binaries(&self) -> impl Iterator<Item = (&String, &Vec<String>)> {
+        self.binarys_indexed.iter()
     }
 }
 
@@ -37,6 +92,7 @@ pub struct Store {
     branches: HashMap<String, BinaryProgressStore>,
 }
 impl Store {
+    #[must_use]
     pub fn new(store_path: &str) -> Self {
         Self {
             store_path: store_path.to_string(),
@@ -52,15 +108,32 @@ pub struct StorageProvider {
 impl StorageProvider {
     /// Gets the store for a given branch.
     pub fn get_or_create_branch_store(&mut self, name: &str) -> &mut BinaryProgressStore {
-        self.store.branches.entry(name.to_string()).or_insert_with(BinaryProgressStore::new)
+        self.store.branches.entry(name.to_string()).or_default()
+    }
+    /// Gets the store for a given branch, if one has been recorded.
+    #[must_use]
+    pub fn branch_store(&self, name: &str) -> Option<&BinaryProgressStore> {
+        self.store.branches.get(name)
     }
     /// Flush the store to disk.
+    #[must_use]
     pub fn flush(&self) ->Option<()> {
         let file = File::create(self.path.clone()).ok()?;
         serde_yaml::to_writer(file, &self.store).ok()?;
         Some(())
     }
+    /// Opens an existing store without creating anything on disk.
+    #[must_use]
+    pub fn open(path: &Path) -> Option<Self> {
+        let progress_file = Path::new(path).join("progress.yaml");
+        let store = serde_yaml::from_reader(File::open(&progress_file).ok()?).ok()?;
+        Some(Self {
+            path: progress_file.as_os_str().to_str()?.to_string(),
+            store,
+        })
+    }
     /// Create a new store.
+    #[must_use]
     pub fn new_or_create(path: &Path) -> Option<Self> {
         let _ = std::fs::create_dir_all(path);
         let progress_file = Path::new(path).join("progress.yaml");
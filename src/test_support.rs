@@ -0,0 +1,99 @@
+//! Winbindex metadata builders shared by the unit tests.
+
+use crate::winbindex_utils::{WinbindexEntry, WinbindexFileData};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// Machine types as written by Winbindex.
+pub const AMD64: u64 = 34404;
+pub const ARM64: u64 = 43620;
+pub const ARM: u64 = 452;
+
+/// Name of the binary every test entry belongs to.
+pub const BINARY: &str = "test.dll";
+
+/// An update a test entry shipped in: the KB, the OS build and the release date.
+pub type Update<'a> = (&'a str, &'a str, i64);
+
+/// The sha256 test entries are keyed by, derived from the version and machine type.
+pub fn sha256(version: &str, machine: u64) -> String {
+    format!("{version}-{machine}")
+}
+
+/// The metadata of one version of [`BINARY`] as Winbindex writes it. Entries without
+/// `downloadable` lack the virtual size, so no download URL can be built for them.
+pub fn entry_json(version: &str, machine: u64, updates: &[Update], downloadable: bool) -> Value {
+    let mut file_info = json!({
+        "size": 100, "md5": "x", "sha1": "y", "machineType": machine, "timestamp": 1_600_000_000,
+        "version": format!("{version} (WinBuild.160101.0800)"),
+    });
+    if downloadable {
+        file_info["virtualSize"] = json!(4096);
+    }
+    let builds: serde_json::Map<String, Value> = updates
+        .iter()
+        .map(|(kb, build, created)| {
+            let build = json!({
+                "updateInfo": {"arch": "amd64", "build": build, "created": created, "title": format!("Cumulative Update ({kb})")},
+                "assemblies": {"a": {
+                    "assemblyIdentity": {
+                        "name": "n", "version": version, "processorArchitecture": "amd64", "language": "neutral",
+                        "buildType": "release", "publicKeyToken": "t", "versionScope": "s",
+                    },
+                    "attributes": [{
+                        "name": BINARY, "destinationPath": "$(runtime.system32)", "sourceName": BINARY,
+                        "importPath": "i", "sourcePath": "s",
+                    }],
+                }},
+            });
+            ((*kb).to_string(), build)
+        })
+        .collect();
+    json!({"fileInfo": file_info, "windowsVersions": {"builds": builds}})
+}
+
+/// As [`entry_json`], parsed into an entry the way `read_entries` would.
+pub fn entry(version: &str, machine: u64, updates: &[Update], downloadable: bool) -> WinbindexEntry {
+    let mut entry: WinbindexEntry = serde_json::from_value(entry_json(version, machine, updates, downloadable)).unwrap();
+    if let Some(file_info) = entry.file_info.as_mut() {
+        file_info.sha256 = Some(sha256(version, machine));
+    }
+    entry.name = BINARY.to_string();
+    entry
+}
+
+/// An entry shipped in a single update named after its version, ie. `KB3` for `10.0.1.3`.
+pub fn simple_entry(version: &str, machine: u64, downloadable: bool) -> WinbindexEntry {
+    let revision = version.rsplit('.').next().unwrap();
+    let kb = format!("KB{revision}");
+    entry(version, machine, &[(&kb, version, revision.parse().unwrap())], downloadable)
+}
+
+pub fn file_data(entries: impl IntoIterator<Item = WinbindexEntry>) -> WinbindexFileData {
+    WinbindexFileData::new(
+        entries
+            .into_iter()
+            .map(|entry| (entry.get_sha256().unwrap(), entry))
+            .collect(),
+    )
+}
+
+/// A fresh directory named after the test using it, removed when dropped.
+pub struct TempDir(PathBuf);
+impl TempDir {
+    pub fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("winbindex_differ_{test}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
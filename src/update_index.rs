@@ -0,0 +1,325 @@
+//! Indexes the tracked binaries of a branch by the update they changed in, so a whole update can
+//! be looked at, and diffed, in one go, ie. "which of our binaries changed in KB5034441?".
+//!
+//! Cumulative updates keep listing files that did not change, so an entry is only indexed under
+//! the update(s) it was first released in.
+
+use crate::diff_config::{ConfigFile, FileConfig};
+use crate::ghidriff_utils::GhidriffOptions;
+use crate::pipeline::{
+    self, BinaryPlan, DiffPlan, PipelineError, PlannedDiff, PlannedEntry, SkipReason, SkippedEntry,
+};
+use crate::winbindex_utils::{Provenance, WinbindexEntry, WinbindexFileData};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// A binary that changed in an update: the entry it shipped with and the one it replaced.
+#[derive(Serialize, Clone)]
+pub struct UpdateChange {
+    pub binary: String,
+    pub arch: String,
+    pub new: PlannedEntry,
+    /// `None` if this is the first version of the binary for the architecture.
+    pub previous: Option<PlannedEntry>,
+}
+
+/// What is needed to diff a binary once its changes have been indexed.
+#[derive(Clone)]
+struct IndexedBinary {
+    ghidriff: GhidriffOptions,
+    provenance: Option<Provenance>,
+}
+
+/// Changes across every binary of one branch, keyed by KB (ie. `KB5034441`) and by OS build
+/// (ie. `22621.3007`).
+#[derive(Clone, Default)]
+pub struct UpdateIndex {
+    pub branch: String,
+    by_kb: BTreeMap<String, Vec<UpdateChange>>,
+    by_build: BTreeMap<String, Vec<UpdateChange>>,
+    binaries: BTreeMap<String, IndexedBinary>,
+}
+
+/// The updates `entry` was first released in, as `(kb, build)`, without duplicates. Every update is
+/// returned if none of them has a release date.
+fn first_updates(entry: &WinbindexEntry) -> BTreeSet<(Option<String>, String)> {
+    let released = entry.get_release_date();
+    entry
+        .get_builds()
+        .map(|(_, build)| build.get_update_info())
+        .filter(|info| released.is_none() || info.get_created() == released)
+        .map(|info| (info.get_kb(), info.get_build().to_string()))
+        .collect()
+}
+
+impl UpdateIndex {
+    #[must_use]
+    pub fn new(branch_name: &str) -> Self {
+        Self {
+            branch: branch_name.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Loads and indexes every binary tracked by `branch_name`, honouring each file's filters.
+    ///
+    /// # Errors
+    /// Fails if the branch has not been cloned, or its files can't be resolved or their metadata
+    /// can't be loaded.
+    pub fn for_branch(config_file: &ConfigFile, branch_name: &str) -> Result<Self, PipelineError> {
+        let mut index = Self::new(branch_name);
+        config_file
+            .require_clone(branch_name)
+            .map_err(PipelineError::Config)?;
+        let resolved = config_file
+            .resolve_files(branch_name)
+            .map_err(PipelineError::Config)?;
+        for file in &resolved.files {
+            let file_data = pipeline::load_file_data(config_file, branch_name, &file.name)
+                .map_err(PipelineError::Winbindex)?;
+            let file = FileConfig {
+                ghidriff: config_file.ghidriff_options(branch_name, Some(file)),
+                ..file.clone()
+            };
+            index.add(&file, &file_data);
+        }
+        Ok(index)
+    }
+
+    /// Indexes every entry of `file_data` accepted by the filters of `file`.
+    pub fn add(&mut self, file: &FileConfig, file_data: &WinbindexFileData) {
+        self.binaries.insert(
+            file.name.clone(),
+            IndexedBinary {
+                ghidriff: file.ghidriff.clone(),
+                provenance: file_data.provenance().cloned(),
+            },
+        );
+        let file_data = file.filter(file_data);
+        let predecessors = file_data.predecessors(false);
+        for entry in file_data.data.values() {
+            let change = UpdateChange {
+                binary: file.name.clone(),
+                arch: pipeline::arch_name(entry),
+                new: entry.into(),
+                previous: entry
+                    .get_sha256()
+                    .and_then(|sha256| predecessors.get(&sha256))
+                    .map(|&previous| previous.into()),
+            };
+            let updates = first_updates(entry);
+            let kbs: BTreeSet<String> = updates.iter().filter_map(|(kb, _)| kb.clone()).collect();
+            let builds: BTreeSet<String> = updates.into_iter().map(|(_, build)| build).collect();
+            for kb in kbs {
+                self.by_kb.entry(kb).or_default().push(change.clone());
+            }
+            for build in builds {
+                self.by_build.entry(build).or_default().push(change.clone());
+            }
+        }
+    }
+
+    /// Every KB with at least one change, sorted.
+    pub fn kbs(&self) -> impl Iterator<Item = &str> {
+        self.by_kb.keys().map(String::as_str)
+    }
+
+    /// Every OS build with at least one change, sorted.
+    pub fn builds(&self) -> impl Iterator<Item = &str> {
+        self.by_build.keys().map(String::as_str)
+    }
+
+    /// The changes in `update`, either a KB (ie. `KB5034441`, case insensitive), an exact OS build
+    /// (ie. `22621.3007`) or any revision of one (ie. `22621`). Sorted by binary, architecture
+    /// and version.
+    #[must_use]
+    pub fn changes(&self, update: &str) -> Vec<&UpdateChange> {
+        let mut changes: Vec<&UpdateChange> = if update.to_uppercase().starts_with("KB") {
+            self.by_kb.get(&update.to_uppercase()).into_iter().flatten().collect()
+        } else {
+            self.by_build
+                .iter()
+                .filter(|(build, _)| {
+                    build.as_str() == update
+                        || build
+                            .strip_prefix(update)
+                            .is_some_and(|revision| revision.starts_with('.'))
+                })
+                .flat_map(|(_, changes)| changes)
+                .collect()
+        };
+        let mut seen = HashSet::new();
+        changes.retain(|change| seen.insert((&change.binary, &change.new.sha256)));
+        changes.sort_by_cached_key(|change| {
+            (change.binary.clone(), change.arch.clone(), change.new.entry.get_version())
+        });
+        changes
+    }
+
+    /// Plans a diff against the previous version for every binary that changed in `update`.
+    /// Changes are skipped with `NoDownloadUrl` if the new version can't be downloaded and with
+    /// `PreviousNotDownloadable` if the previous one can't. Nothing is recorded to the progress
+    /// store when the plan runs.
+    #[must_use]
+    pub fn plan(&self, update: &str) -> DiffPlan {
+        let mut plan = DiffPlan::default();
+        for change in self.changes(update) {
+            if plan.binaries.last().is_none_or(|last| last.binary != change.binary) {
+                let indexed = self.binaries.get(&change.binary).cloned();
+                plan.binaries.push(BinaryPlan {
+                    branch: self.branch.clone(),
+                    binary: change.binary.clone(),
+                    diffs: Vec::new(),
+                    skipped: Vec::new(),
+                    record: Vec::new(),
+                    ghidriff: indexed.as_ref().map(|b| b.ghidriff.clone()).unwrap_or_default(),
+                    provenance: indexed.and_then(|b| b.provenance),
+                    selector_hash: None,
+                });
+            }
+            let Some(binary) = plan.binaries.last_mut() else {
+                continue;
+            };
+            let new = &change.new.entry;
+            match &change.previous {
+                _ if new.get_download_url().is_none() => {
+                    binary.skipped.push(pipeline::skip(new, SkipReason::NoDownloadUrl));
+                }
+                // Diffing against an older version would attribute changes from the updates in
+                // between to this one.
+                Some(previous) if previous.entry.get_download_url().is_none() => {
+                    binary.skipped.push(SkippedEntry {
+                        previous_sha256: Some(previous.sha256.clone()),
+                        previous_version: Some(previous.version.clone()),
+                        ..pipeline::skip(new, SkipReason::PreviousNotDownloadable)
+                    });
+                }
+                None => binary.skipped.push(pipeline::skip(new, SkipReason::NoPreviousVersion)),
+                Some(previous) => binary.diffs.push(PlannedDiff {
+                    arch: change.arch.clone(),
+                    old: previous.clone(),
+                    new: change.new.clone(),
+                }),
+            }
+        }
+        plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{entry, file_data, simple_entry, AMD64, ARM64, BINARY};
+
+    fn file() -> FileConfig {
+        FileConfig { name: BINARY.to_string(), ..FileConfig::default() }
+    }
+
+    fn index(entries: impl IntoIterator<Item = WinbindexEntry>) -> UpdateIndex {
+        let mut index = UpdateIndex::new("main");
+        index.add(&file(), &file_data(entries));
+        index
+    }
+
+    fn versions(changes: &[&UpdateChange]) -> Vec<(String, Option<String>)> {
+        changes
+            .iter()
+            .map(|c| (c.new.version.clone(), c.previous.as_ref().map(|p| p.version.clone())))
+            .collect()
+    }
+
+    fn change(new: &str, previous: Option<&str>) -> (String, Option<String>) {
+        (new.to_string(), previous.map(str::to_string))
+    }
+
+    #[test]
+    fn first_updates_keeps_the_earliest_release() {
+        let entry = entry(
+            "10.0.1.1",
+            AMD64,
+            &[("KB1", "22621.1", 1), ("KB2", "22621.2", 2), ("KB3", "22621.3", 1)],
+            true,
+        );
+        assert_eq!(
+            first_updates(&entry).into_iter().collect::<Vec<_>>(),
+            [
+                (Some("KB1".to_string()), "22621.1".to_string()),
+                (Some("KB3".to_string()), "22621.3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_are_looked_up_by_kb_and_build() {
+        let index = index([
+            entry("10.0.1.1", AMD64, &[("KB1", "22621.1", 1)], true),
+            entry("10.0.1.2", AMD64, &[("KB2", "22621.2", 2), ("KB3", "22631.2", 2)], true),
+            entry("10.0.1.3", AMD64, &[("KB3", "22631.3", 3)], true),
+        ]);
+        assert_eq!(index.kbs().collect::<Vec<_>>(), ["KB1", "KB2", "KB3"]);
+        assert_eq!(index.builds().collect::<Vec<_>>(), ["22621.1", "22621.2", "22631.2", "22631.3"]);
+        assert_eq!(versions(&index.changes("kb2")), [change("10.0.1.2", Some("10.0.1.1"))]);
+        // 10.0.1.2 was first released in KB3 too, but 10.0.1.3 wasn't in KB2.
+        assert_eq!(
+            versions(&index.changes("KB3")),
+            [change("10.0.1.2", Some("10.0.1.1")), change("10.0.1.3", Some("10.0.1.2"))]
+        );
+        assert_eq!(versions(&index.changes("22621.1")), [change("10.0.1.1", None)]);
+        assert_eq!(
+            versions(&index.changes("22621")),
+            [change("10.0.1.1", None), change("10.0.1.2", Some("10.0.1.1"))]
+        );
+        assert!(index.changes("2262").is_empty());
+        assert!(index.changes("KB4").is_empty());
+    }
+
+    #[test]
+    fn changes_are_indexed_once_per_update() {
+        // Both KBs ship the same build, ie. a security update and its dynamic update.
+        let updates = [("KB1", "22621.1", 1), ("KB2", "22621.1", 1)];
+        let index = index([entry("10.0.1.1", AMD64, &updates, true)]);
+        assert_eq!(index.by_build["22621.1"].len(), 1);
+        assert_eq!(versions(&index.changes("22621.1")), [change("10.0.1.1", None)]);
+        assert_eq!(versions(&index.changes("KB2")), [change("10.0.1.1", None)]);
+    }
+
+    #[test]
+    fn plan_diffs_against_the_previous_version() {
+        let index = index([
+            simple_entry("10.0.1.1", AMD64, true),
+            simple_entry("10.0.1.2", AMD64, true),
+            simple_entry("10.0.1.3", AMD64, false),
+            simple_entry("10.0.1.4", AMD64, true),
+            simple_entry("10.0.1.5", ARM64, true),
+        ]);
+        let plan = index.plan("KB2");
+        let [binary] = plan.binaries.as_slice() else { panic!("expected one binary") };
+        assert_eq!(binary.binary, BINARY);
+        assert!(binary.skipped.is_empty());
+        let diffs: Vec<_> = binary
+            .diffs
+            .iter()
+            .map(|d| (d.old.version.as_str(), d.new.version.as_str()))
+            .collect();
+        assert_eq!(diffs, [("10.0.1.1", "10.0.1.2")]);
+        assert!(binary.record.is_empty());
+
+        let skipped = |update: &str| {
+            let plan = index.plan(update);
+            assert!(plan.binaries.iter().all(|b| b.diffs.is_empty()));
+            plan.binaries.into_iter().flat_map(|b| b.skipped).collect::<Vec<_>>()
+        };
+        let [no_url] = skipped("KB3").try_into().unwrap_or_else(|_| panic!("expected one skip"));
+        assert_eq!((no_url.version.as_str(), no_url.reason), ("10.0.1.3", SkipReason::NoDownloadUrl));
+        // 10.0.1.3 is the one that can't be downloaded, so it is named rather than 10.0.1.4.
+        let [previous] = skipped("KB4").try_into().unwrap_or_else(|_| panic!("expected one skip"));
+        assert_eq!(
+            (previous.version.as_str(), previous.reason, previous.previous_version.as_deref()),
+            ("10.0.1.4", SkipReason::PreviousNotDownloadable, Some("10.0.1.3"))
+        );
+        assert_eq!(previous.describe_reason(), "PreviousNotDownloadable: 10.0.1.3 10.0.1.3-34404");
+        let [first] = skipped("KB5").try_into().unwrap_or_else(|_| panic!("expected one skip"));
+        assert_eq!((first.version.as_str(), first.reason), ("10.0.1.5", SkipReason::NoPreviousVersion));
+        assert!(index.plan("KB6").binaries.is_empty());
+    }
+}
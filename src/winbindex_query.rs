@@ -0,0 +1,150 @@
+//! Composable queries over the entries of a `WinbindexFileData`, ie.
+//! `file_data.query(&EntryQuery::new().with_arch(Arch::Amd64).with_kb("KB5034441"))`.
+
+use crate::winbindex_utils::{Arch, BinaryVersion, WinbindexEntry, WinbindexFileData};
+
+/// Order of the entries returned by [`WinbindexFileData::query`]. Ties are broken by sha256 so
+/// results are stable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortKey {
+    /// Assembly version, as used for diffing.
+    #[default]
+    Version,
+    /// Version from the PE version resource, entries without one first.
+    FileVersion,
+    /// Release date of the first update the entry shipped in, entries without one first.
+    ReleaseDate,
+    Sha256,
+}
+
+/// Filters and ordering for [`WinbindexFileData::query`]. Every filter that is set must match.
+#[derive(Debug, Clone, Default)]
+pub struct EntryQuery {
+    arches: Vec<Arch>,
+    min_version: Option<BinaryVersion>,
+    max_version: Option<BinaryVersion>,
+    kb: Option<String>,
+    build: Option<String>,
+    released_after: Option<i64>,
+    released_before: Option<i64>,
+    signing_status: Option<String>,
+    download_url: Option<bool>,
+    sort: SortKey,
+    descending: bool,
+}
+
+impl EntryQuery {
+    /// A query matching every entry, sorted by version.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Only entries for `arch`. May be repeated to accept several architectures.
+    #[must_use]
+    pub fn with_arch(mut self, arch: Arch) -> Self {
+        self.arches.push(arch);
+        self
+    }
+    /// Only entries whose version is within the inclusive range.
+    #[must_use]
+    pub const fn with_version_range(mut self, min: Option<BinaryVersion>, max: Option<BinaryVersion>) -> Self {
+        self.min_version = min;
+        self.max_version = max;
+        self
+    }
+    /// Only entries that shipped in the update `kb`, ie. `KB5034441`.
+    #[must_use]
+    pub fn with_kb(mut self, kb: &str) -> Self {
+        self.kb = Some(kb.to_string());
+        self
+    }
+    /// Only entries that shipped in an update for the OS build `build`, either exactly
+    /// (ie. `22621.3007`) or any revision of it (ie. `22621`).
+    #[must_use]
+    pub fn with_build(mut self, build: &str) -> Self {
+        self.build = Some(build.to_string());
+        self
+    }
+    /// Only entries first released within the inclusive window, in seconds since the Unix epoch.
+    #[must_use]
+    pub const fn with_release_window(mut self, after: Option<i64>, before: Option<i64>) -> Self {
+        self.released_after = after;
+        self.released_before = before;
+        self
+    }
+    /// Only entries with the given signing status, ie. `Signed`, compared case insensitively.
+    #[must_use]
+    pub fn with_signing_status(mut self, status: &str) -> Self {
+        self.signing_status = Some(status.to_string());
+        self
+    }
+    /// Only entries that can (`true`) or can't (`false`) be downloaded from the symbol server.
+    #[must_use]
+    pub const fn with_download_url(mut self, downloadable: bool) -> Self {
+        self.download_url = Some(downloadable);
+        self
+    }
+    #[must_use]
+    pub const fn sorted_by(mut self, sort: SortKey) -> Self {
+        self.sort = sort;
+        self
+    }
+    /// Reverses the order, ie. newest first.
+    #[must_use]
+    pub const fn descending(mut self) -> Self {
+        self.descending = true;
+        self
+    }
+
+    /// Whether `entry` passes every filter of this query.
+    #[must_use]
+    pub fn matches(&self, entry: &WinbindexEntry) -> bool {
+        let version = entry.get_version();
+        let released = entry.get_release_date();
+        (self.arches.is_empty() || entry.get_arch().is_some_and(|arch| self.arches.contains(&arch)))
+            && self.min_version.as_ref().is_none_or(|min| &version >= min)
+            && self.max_version.as_ref().is_none_or(|max| &version <= max)
+            && self.kb.as_ref().is_none_or(|kb| entry.in_update(kb))
+            && self.build.as_ref().is_none_or(|build| {
+                entry.get_builds().any(|(_, b)| {
+                    let shipped = b.get_update_info().get_build();
+                    shipped == build
+                        || shipped
+                            .strip_prefix(build.as_str())
+                            .is_some_and(|revision| revision.starts_with('.'))
+                })
+            })
+            && self.released_after.is_none_or(|after| released.is_some_and(|r| r >= after))
+            && self.released_before.is_none_or(|before| released.is_some_and(|r| r <= before))
+            && self.signing_status.as_ref().is_none_or(|status| {
+                entry
+                    .get_file_info()
+                    .and_then(|info| info.get_signing_status())
+                    .is_some_and(|s| s.eq_ignore_ascii_case(status))
+            })
+            && self.download_url.is_none_or(|wanted| entry.get_download_url().is_some() == wanted)
+    }
+
+    /// Orders `entries` by the sort key of this query.
+    pub fn sort(&self, entries: &mut [&WinbindexEntry]) {
+        match self.sort {
+            SortKey::Version => entries.sort_by_cached_key(|v| (v.get_version(), v.get_sha256())),
+            SortKey::FileVersion => entries.sort_by_cached_key(|v| (v.get_file_version(), v.get_sha256())),
+            SortKey::ReleaseDate => entries.sort_by_cached_key(|v| (v.get_release_date(), v.get_sha256())),
+            SortKey::Sha256 => entries.sort_by_cached_key(|v| v.get_sha256()),
+        }
+        if self.descending {
+            entries.reverse();
+        }
+    }
+}
+
+impl WinbindexFileData {
+    /// The entries matching `query`, in the order it asks for.
+    #[must_use]
+    pub fn query(&self, query: &EntryQuery) -> Vec<&WinbindexEntry> {
+        let mut entries: Vec<&WinbindexEntry> = self.data.values().filter(|v| query.matches(v)).collect();
+        query.sort(&mut entries);
+        entries
+    }
+}
@@ -1,18 +1,20 @@
 //! Loads Winbindex metadata for a given file, and exposes operations on it.
 
 use flate2::read::GzDecoder;
+use serde::de::{DeserializeSeed, MapAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
 use std::{
     cmp::Ordering,
     collections::HashMap,
     fs::File,
-    io::Read,
+    io::{BufReader, Read},
     path::{Path, PathBuf},
 };
 
+/// A file installed by an assembly.
 #[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone)]
-struct Attribute {
+pub struct Attribute {
     name: String,
     #[serde(alias = "destinationPath")]
     destination_path: String,
@@ -23,9 +25,37 @@ struct Attribute {
     #[serde(alias = "sourcePath")]
     source_path: String,
 }
+impl Attribute {
+    /// Name of the installed file.
+    #[must_use]
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    /// Directory the file is installed to, ie. `$(runtime.system32)`.
+    #[must_use]
+    pub fn get_destination_path(&self) -> &str {
+        &self.destination_path
+    }
+    /// Name of the file within the update package.
+    #[must_use]
+    pub fn get_source_name(&self) -> &str {
+        &self.source_name
+    }
+    /// Directory the file was taken from when the update was built, ie. `$(build.nttree)\`.
+    #[must_use]
+    pub fn get_import_path(&self) -> &str {
+        &self.import_path
+    }
+    /// Directory of the file within the update package, ie. `.\`.
+    #[must_use]
+    pub fn get_source_path(&self) -> &str {
+        &self.source_path
+    }
+}
 
+/// Identity of the assembly (component) a file shipped in.
 #[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone)]
-struct AssemblyIdentity {
+pub struct AssemblyIdentity {
     name: String,
     version: String,
     #[serde(alias = "processorArchitecture")]
@@ -38,28 +68,142 @@ struct AssemblyIdentity {
     #[serde(alias = "versionScope")]
     version_scope: String,
 }
+impl AssemblyIdentity {
+    /// Component name, ie. `Microsoft-Windows-Ntdll`.
+    #[must_use]
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    /// Version of the assembly, `None` if it isn't a four part version.
+    #[must_use]
+    pub fn get_version(&self) -> Option<BinaryVersion> {
+        BinaryVersion::parse(&self.version)
+    }
+    /// Architecture of the assembly as written by Winbindex, ie. `amd64` or `wow64`.
+    #[must_use]
+    pub fn get_processor_architecture(&self) -> &str {
+        &self.processor_architecture
+    }
+    /// Language of the assembly, ie. `neutral` or `en-US`.
+    #[must_use]
+    pub fn get_language(&self) -> &str {
+        &self.language
+    }
+    /// Build type, ie. `release`.
+    #[must_use]
+    pub fn get_build_type(&self) -> &str {
+        &self.build_type
+    }
+    /// Token of the key the assembly is signed with, ie. `31bf3856ad364e35`.
+    #[must_use]
+    pub fn get_public_key_token(&self) -> &str {
+        &self.public_key_token
+    }
+    /// Whether side by side versions can be installed, ie. `nonSxS`.
+    #[must_use]
+    pub fn get_version_scope(&self) -> &str {
+        &self.version_scope
+    }
+}
 
+/// An assembly of an update, and the files it installs.
 #[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone)]
-struct Assembly {
+pub struct Assembly {
     #[serde(alias = "assemblyIdentity")]
     assembly_identity: AssemblyIdentity,
     attributes: Vec<Attribute>,
 }
+impl Assembly {
+    /// Name, version and architecture of this assembly.
+    #[must_use]
+    pub const fn get_identity(&self) -> &AssemblyIdentity {
+        &self.assembly_identity
+    }
+    /// The files installed by this assembly.
+    #[must_use]
+    pub fn get_attributes(&self) -> &[Attribute] {
+        &self.attributes
+    }
+}
 
+/// The Windows update a build of a file shipped in.
 #[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone)]
-struct UpdateInfo {
+pub struct UpdateInfo {
     arch: String,
     build: String,
     created: Number,
     title: String,
 }
+impl UpdateInfo {
+    /// Architecture of the update as written by Winbindex, ie. `x64` or `arm64`.
+    #[must_use]
+    pub fn get_arch(&self) -> &str {
+        &self.arch
+    }
+    /// OS build the update brings Windows to, ie. `22621.3007`.
+    #[must_use]
+    pub fn get_build(&self) -> &str {
+        &self.build
+    }
+    /// Release date of the update, in seconds since the Unix epoch.
+    #[must_use]
+    pub fn get_created(&self) -> Option<i64> {
+        self.created.as_i64()
+    }
+    /// Title of the update, ie. `January 9, 2024—KB5034123 (OS Builds 22621.3007 and 22631.3007)`.
+    #[must_use]
+    pub fn get_title(&self) -> &str {
+        &self.title
+    }
+    /// The first KB number in the title, ie. `KB5034123`.
+    #[must_use]
+    pub fn get_kb(&self) -> Option<String> {
+        self.title_kbs().into_iter().next()
+    }
+    /// Every KB number in the title, upper case.
+    fn title_kbs(&self) -> Vec<String> {
+        let upper = self.title.to_uppercase();
+        upper
+            .match_indices("KB")
+            .filter_map(|(start, _)| {
+                let digits: String = upper[start + 2..].chars().take_while(char::is_ascii_digit).collect();
+                (!digits.is_empty()).then(|| format!("KB{digits}"))
+            })
+            .collect()
+    }
+}
 
+/// A file as shipped in one update.
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct Build {
     #[serde(alias = "updateInfo")]
     update_info: UpdateInfo,
     assemblies: HashMap<String, Assembly>,
 }
+impl Build {
+    #[must_use]
+    pub const fn get_update_info(&self) -> &UpdateInfo {
+        &self.update_info
+    }
+    /// Assemblies of the update containing the file, keyed by their full name.
+    pub fn get_assemblies(&self) -> impl Iterator<Item = (&String, &Assembly)> {
+        self.assemblies.iter()
+    }
+    /// Every directory the file is installed to by this update, without duplicates.
+    #[must_use]
+    pub fn get_destination_paths(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = self
+            .assemblies
+            .values()
+            .flat_map(|assembly| assembly.attributes.iter().map(Attribute::get_destination_path))
+            .collect();
+        paths.sort_unstable();
+        paths.dedup();
+        paths
+    }
+}
+
+/// Properties of the file itself, as extracted by Winbindex.
 #[derive(Serialize, Deserialize, Eq, Hash, PartialEq, Clone)]
 pub struct FileInfo {
     size: Number,
@@ -80,6 +224,68 @@ pub struct FileInfo {
     #[serde(alias = "signingDate")]
     signing_date: Option<Vec<String>>,
 }
+impl FileInfo {
+    /// File size in bytes.
+    #[must_use]
+    pub fn get_size(&self) -> Option<u64> {
+        self.size.as_u64()
+    }
+    /// MD5 of the file, hex encoded.
+    #[must_use]
+    pub fn get_md5(&self) -> Option<&str> {
+        self.md5.as_deref()
+    }
+    /// SHA-1 of the file, hex encoded.
+    #[must_use]
+    pub fn get_sha1(&self) -> Option<&str> {
+        self.sha1.as_deref()
+    }
+    /// SHA-256 of the file, the key of its entry in the metadata.
+    #[must_use]
+    pub fn get_sha256(&self) -> Option<&str> {
+        self.sha256.as_deref()
+    }
+    /// PE machine type, ie. `34404` for amd64.
+    #[must_use]
+    pub fn get_machine_type(&self) -> Option<u64> {
+        self.machine_type.as_u64()
+    }
+    /// PE header timestamp.
+    #[must_use]
+    pub fn get_timestamp(&self) -> Option<u64> {
+        self.timestamp.as_u64()
+    }
+    /// `SizeOfImage` from the PE header.
+    #[must_use]
+    pub fn get_virtual_size(&self) -> Option<u64> {
+        self.virtual_size.as_ref()?.as_u64()
+    }
+    /// Version resource as written, ie. `10.0.22621.3007 (WinBuild.160101.0800)`.
+    #[must_use]
+    pub fn get_version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+    /// `FileDescription` from the version resource.
+    #[must_use]
+    pub fn get_description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+    /// Signature verification result, ie. `Signed`.
+    #[must_use]
+    pub fn get_signing_status(&self) -> Option<&str> {
+        self.signing_status.as_deref()
+    }
+    /// Signature type, ie. `Overlay` or `Catalog file`.
+    #[must_use]
+    pub fn get_signature_type(&self) -> Option<&str> {
+        self.signature_type.as_deref()
+    }
+    /// Signing dates as written by Winbindex, ie. `2023-12-14T04:33:08`.
+    #[must_use]
+    pub fn get_signing_dates(&self) -> &[String] {
+        self.signing_date.as_deref().unwrap_or_default()
+    }
+}
 impl Default for FileInfo {
     fn default() -> Self {
         Self {
@@ -103,6 +309,12 @@ impl Default for FileInfo {
 pub struct WindowsVersion {
     pub builds: Option<HashMap<String, Build>>,
 }
+impl WindowsVersion {
+    /// The updates the file shipped in, keyed by Winbindex's name for them, usually the KB.
+    pub fn get_builds(&self) -> impl Iterator<Item = (&String, &Build)> {
+        self.builds.iter().flatten()
+    }
+}
 
 #[derive(Debug, Eq, Hash, PartialEq, Clone)]
 pub struct BinaryVersion {
@@ -112,6 +324,7 @@ pub struct BinaryVersion {
     build: u32,
 }
 impl BinaryVersion {
+    #[must_use]
     pub fn parse(version_string: &str) -> Option<Self> {
         let parts: Vec<&str> = version_string.split('.').collect();
         if parts.len() != 4 {
@@ -131,6 +344,24 @@ impl BinaryVersion {
         })
     }
 }
+impl Serialize for BinaryVersion {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+impl<'de> Deserialize<'de> for BinaryVersion {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = String::deserialize(deserializer)?;
+        Self::parse(&version).ok_or_else(|| {
+            serde::de::Error::custom(format!("{version:?} is not a version like 10.0.22621.3296"))
+        })
+    }
+}
+impl std::fmt::Display for BinaryVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.major, self.minor, self.patch, self.build)
+    }
+}
 impl PartialOrd for BinaryVersion {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -157,12 +388,14 @@ impl Default for BinaryVersion {
     }
 }
 
-#[derive(Eq, PartialEq, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
 pub enum Arch {
     X86,
     Amd64,
     Arm64,
     Arm,
+    #[serde(skip)]
     Invalid,
 }
 impl From<&str> for Arch {
@@ -193,6 +426,17 @@ impl From<Arch> for String {
 pub struct SymbolServerDownloadUrl {
     pub url: String,
 }
+/// Where a `WinbindexEntry` was loaded from.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Provenance {
+    /// Upstream branch of the Winbindex repository, ie. `gh-pages`.
+    pub branch: String,
+    pub repo_url: String,
+    /// Commit the metadata was read at, if the repository could be inspected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct WinbindexEntry {
     #[serde(alias = "fileInfo")]
@@ -203,11 +447,15 @@ pub struct WinbindexEntry {
     pub repo: String,
     #[serde(skip)]
     pub name: String,
+    #[serde(skip)]
+    pub provenance: Option<Provenance>,
 }
 impl WinbindexEntry {
+    #[must_use]
     pub fn get_binary_dlname(&self) -> Option<String> {
         Some(format!("{}_{}", self.get_sha256()?, self.get_name()))
     }
+    #[must_use]
     pub fn get_arch(&self) -> Option<Arch> {
         //https://learn.microsoft.com/en-us/dotnet/api/system.reflection.portableexecutable.machine?view=net-8.0
         match self.file_info.as_ref()?.machine_type.as_u64()?{
@@ -218,6 +466,7 @@ impl WinbindexEntry {
             _ => Some(Arch::Invalid)
         }
     }
+    #[must_use]
     pub fn get_version(&self) -> BinaryVersion {
         let builds = &self.windows_version.builds.clone().unwrap_or_default();
         let values = builds.values();
@@ -230,15 +479,72 @@ impl WinbindexEntry {
         }
         BinaryVersion::default()
     }
+    #[must_use]
     pub fn get_name(&self) ->String {
         self.name.clone()
     }
+    #[must_use]
     pub fn get_sha256(&self) -> Option<String> {
         self.file_info.clone()?.sha256
     }
+    /// Parses the version from the PE version resource, ie. `10.0.22621.3296 (WinBuild.160101.0800)`.
+    #[must_use]
+    pub fn get_file_version(&self) -> Option<BinaryVersion> {
+        let version = self.file_info.as_ref()?.version.as_ref()?;
+        BinaryVersion::parse(version.split_whitespace().next()?)
+    }
+    /// Checks whether this entry shipped as part of the given update, ie. `KB5034441`. The KB
+    /// must match a build key or a KB number in an update title exactly, case insensitively.
+    #[must_use]
+    pub fn in_update(&self, kb: &str) -> bool {
+        self.get_builds().any(|(name, build)| {
+            name.eq_ignore_ascii_case(kb)
+                || build.update_info.title_kbs().iter().any(|title_kb| title_kb.eq_ignore_ascii_case(kb))
+        })
+    }
+    /// Properties of the file itself, `None` for entries Winbindex could not extract them for.
+    #[must_use]
+    pub const fn get_file_info(&self) -> Option<&FileInfo> {
+        self.file_info.as_ref()
+    }
+    /// The updates this file shipped in, keyed by Winbindex's name for them, usually the KB.
+    pub fn get_builds(&self) -> impl Iterator<Item = (&String, &Build)> {
+        self.windows_version.get_builds()
+    }
+    /// KB numbers of the updates this file shipped in, sorted and without duplicates.
+    #[must_use]
+    pub fn get_kbs(&self) -> Vec<String> {
+        let mut kbs: Vec<String> = self
+            .get_builds()
+            .filter_map(|(_, build)| build.update_info.get_kb())
+            .collect();
+        kbs.sort();
+        kbs.dedup();
+        kbs
+    }
+    /// Release date of the first update this file shipped in, in seconds since the Unix epoch.
+    #[must_use]
+    pub fn get_release_date(&self) -> Option<i64> {
+        self.get_builds()
+            .filter_map(|(_, build)| build.update_info.get_created())
+            .min()
+    }
+    /// Every directory this file is installed to, across all updates, without duplicates.
+    #[must_use]
+    pub fn get_destination_paths(&self) -> Vec<&str> {
+        let mut paths: Vec<&str> = self
+            .get_builds()
+            .flat_map(|(_, build)| build.get_destination_paths())
+            .collect();
+        paths.sort_unstable();
+        paths.dedup();
+        paths
+    }
+    #[must_use]
     pub fn get_timestamp(&self) -> Option<Number> {
         Some(self.file_info.clone()?.timestamp)
     }
+    #[must_use]
     pub fn get_download_url(&self) -> Option<SymbolServerDownloadUrl> {
         let timestamp: Number = self.get_timestamp()?;
         // TODO: Handle the cases where virual_size is None
@@ -259,42 +565,148 @@ impl WinbindexEntry {
 
         Some(SymbolServerDownloadUrl { url })
     }
+}
 
-    fn set_sha256(&mut self, sha256: String)->Result<(),WinbindexError> {
-        self.file_info.as_mut().ok_or(WinbindexError::NoFileInfo)?.sha256 = Some(sha256);
-        Ok(())
+/// Why an entry of a metadata file was left out of `WinbindexFileData::data`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// The entry doesn't have the shape of a `WinbindexEntry`.
+    Malformed,
+    /// Winbindex has no `fileInfo` for the entry, so it can't be downloaded or diffed.
+    MissingFileInfo,
+}
+
+/// An entry of a metadata file that was skipped while loading it.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    pub sha256: String,
+    pub kind: AnomalyKind,
+    /// The deserialization error, for `Malformed` entries.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.kind, &self.error) {
+            (AnomalyKind::Malformed, Some(error)) => write!(f, "{} is malformed: {error}", self.sha256),
+            (AnomalyKind::Malformed, None) => write!(f, "{} is malformed", self.sha256),
+            (AnomalyKind::MissingFileInfo, _) => write!(f, "{} has no fileInfo", self.sha256),
+        }
     }
 }
 
 pub struct WinbindexFileData {
     pub data: HashMap<String, WinbindexEntry>,
+    /// Entries of the metadata file that were skipped, sorted by sha256.
+    pub anomalies: Vec<Anomaly>,
 }
 
 
 impl WinbindexFileData {
+    #[must_use]
     pub const fn new(data: HashMap<String, WinbindexEntry>) -> Self {
-        Self { data }
+        Self { data, anomalies: Vec::new() }
     }
 
-    pub fn find_previous_for_entry(&self, entry: &WinbindexEntry) -> Option<WinbindexEntry> {
-        let mut by_version: HashMap<BinaryVersion, WinbindexEntry> = HashMap::new();
-        for (_k, v) in self.data.clone() {
-            if v.get_arch() == entry.get_arch() {
-                by_version.insert(v.get_version(), v);
-            }
+    /// Records where every entry was loaded from.
+    pub fn set_provenance(&mut self, provenance: &Provenance) {
+        for entry in self.data.values_mut() {
+            entry.provenance = Some(provenance.clone());
         }
-        let mut v: Vec<_> = by_version.keys().cloned().collect();
-        v.sort();
-        let position = v.iter().position(|r| r == &entry.get_version())?;
+    }
+
+    /// Where the entries were loaded from, if recorded.
+    #[must_use]
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.data.values().find_map(|entry| entry.provenance.as_ref())
+    }
 
-        if position == 0 {
+    /// The entry of the next lower version with the same architecture as `entry`, or `None` if
+    /// `entry` has the lowest version or no entry of its architecture has its version. Entries
+    /// whose version can't be parsed get `BinaryVersion::default()`, which is above every real
+    /// version, so they follow the highest known one. Use [`Self::predecessors`] to look up many
+    /// entries.
+    #[must_use]
+    pub fn find_previous_for_entry(&self, entry: &WinbindexEntry) -> Option<WinbindexEntry> {
+        let arch = entry.get_arch();
+        let version = entry.get_version();
+        let same_arch = || self.data.values().filter(move |v| v.get_arch() == arch);
+        if !same_arch().any(|v| v.get_version() == version) {
             return None;
         }
-        let prev_idx = position - 1;
-        let prev_ver = v.get(prev_idx)?;
-        let prev_entry = by_version.get(prev_ver)?;
+        same_arch()
+            .filter(|v| v.get_version() < version)
+            .max_by_key(|v| (v.get_version(), v.get_sha256()))
+            .cloned()
+    }
+
+    /// The predecessor of every entry, as [`Self::find_previous_for_entry`] finds it, keyed by
+    /// sha256 and worked out in a single pass. With `downloadable_only`, entries without a
+    /// download URL are passed over, so an entry's predecessor is the nearest one that can be
+    /// diffed against.
+    #[must_use]
+    pub fn predecessors(&self, downloadable_only: bool) -> HashMap<String, &WinbindexEntry> {
+        let mut by_arch: HashMap<Option<Arch>, Vec<&WinbindexEntry>> = HashMap::new();
+        for entry in self.data.values() {
+            by_arch.entry(entry.get_arch()).or_default().push(entry);
+        }
+        let mut predecessors = HashMap::new();
+        for mut entries in by_arch.into_values() {
+            entries.sort_by_cached_key(|v| (v.get_version(), v.get_sha256()));
+            let mut previous: Option<&WinbindexEntry> = None;
+            let mut current: Option<&WinbindexEntry> = None;
+            let mut current_version = None;
+            for entry in entries {
+                let version = entry.get_version();
+                if current_version.as_ref() != Some(&version) {
+                    previous = current.take().or(previous);
+                    current_version = Some(version);
+                }
+                if let (Some(sha256), Some(previous)) = (entry.get_sha256(), previous) {
+                    predecessors.insert(sha256, previous);
+                }
+                if !downloadable_only || entry.get_download_url().is_some() {
+                    current = Some(entry);
+                }
+            }
+        }
+        predecessors
+    }
 
-        Some(prev_entry.clone())
+    /// Resolves a single entry from a sha256, a version string (ie. `10.0.22621.3296`) or a KB
+    /// (ie. `KB5034441`), optionally restricted to one architecture.
+    ///
+    /// # Errors
+    /// Returns `EntryNotFound` if nothing matches, or `AmbiguousEntry` if several entries do.
+    pub fn resolve(&self, spec: &str, arch: Option<Arch>) -> Result<WinbindexEntry, WinbindexError> {
+        if let Some(entry) = self.data.get(&spec.to_lowercase()) {
+            return Ok(entry.clone());
+        }
+        let version = BinaryVersion::parse(spec);
+        let is_kb = spec.get(..2).is_some_and(|prefix| prefix.eq_ignore_ascii_case("KB"));
+        let mut matches: Vec<&WinbindexEntry> = self
+            .data
+            .values()
+            .filter(|v| arch.is_none() || v.get_arch() == arch)
+            .filter(|v| {
+                version.as_ref().map_or_else(
+                    || is_kb && v.in_update(spec),
+                    |version| {
+                        v.get_file_version().as_ref() == Some(version)
+                            || &v.get_version() == version
+                    },
+                )
+            })
+            .collect();
+        match matches.len() {
+            0 => Err(WinbindexError::EntryNotFound(spec.to_string())),
+            1 => Ok(matches.remove(0).clone()),
+            _ => Err(WinbindexError::AmbiguousEntry(
+                spec.to_string(),
+                matches.iter().filter_map(|v| v.get_sha256()).collect(),
+            )),
+        }
     }
 }
 #[derive(Debug)]
@@ -305,52 +717,296 @@ pub enum WinbindexError {
     InvalidWinbindexEntryFormatting(serde_json::Error),
     InvalidOsString,
     NoFileInfo,
+    EntryNotFound(String),
+    AmbiguousEntry(String, Vec<String>),
+    GitObject(String),
+}
+impl std::fmt::Display for WinbindexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FileOpen(path) => write!(f, "could not open {}", path.display()),
+            Self::Gzip => write!(f, "could not decompress metadata"),
+            Self::InvalidWinbindexEntryFormatting(e) => write!(f, "invalid metadata: {e}"),
+            Self::InvalidOsString => write!(f, "path is not valid unicode"),
+            Self::NoFileInfo => write!(f, "entry has no fileInfo"),
+            Self::EntryNotFound(spec) => write!(f, "no entry matches {spec}"),
+            Self::AmbiguousEntry(spec, candidates) => write!(
+                f,
+                "{spec} matches {} entries, pass a sha256 or an architecture: {}",
+                candidates.len(),
+                candidates.join(", ")
+            ),
+            Self::GitObject(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// Deserializes the top-level map of a metadata file one entry at a time. Entries that don't
+/// parse or lack `fileInfo` are recorded as anomalies before the next one is read.
+struct EntryStream<'a> {
+    file_name: &'a str,
+    repo: &'a str,
+}
+impl<'de> Visitor<'de> for EntryStream<'_> {
+    type Value = WinbindexFileData;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a map of sha256 to Winbindex entries")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut file_data = WinbindexFileData::new(HashMap::with_capacity(map.size_hint().unwrap_or_default()));
+        while let Some((sha256, value)) = map.next_entry::<String, serde_json::Value>()? {
+            let mut entry = match serde_json::from_value::<WinbindexEntry>(value) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    file_data.anomalies.push(Anomaly {
+                        sha256,
+                        kind: AnomalyKind::Malformed,
+                        error: Some(e.to_string()),
+                    });
+                    continue;
+                }
+            };
+            let Some(file_info) = entry.file_info.as_mut() else {
+                file_data.anomalies.push(Anomaly { sha256, kind: AnomalyKind::MissingFileInfo, error: None });
+                continue;
+            };
+            file_info.sha256 = Some(sha256.clone());
+            entry.repo = self.repo.to_string();
+            entry.name = self.file_name.to_string();
+            file_data.data.insert(sha256, entry);
+        }
+        file_data.anomalies.sort_by(|a, b| a.sha256.cmp(&b.sha256));
+        Ok(file_data)
+    }
+}
+impl<'de> DeserializeSeed<'de> for EntryStream<'_> {
+    type Value = WinbindexFileData;
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+/// Parses decompressed Winbindex metadata from `reader` for the binary `file_name` of the branch
+/// `repo`. Entries that are malformed or lack `fileInfo` are left out and listed in `anomalies`.
+///
+/// Entries are parsed and filtered as they are read, so peak memory stays close to the size of
+/// the retained entries rather than the whole document.
+///
+/// # Errors
+/// Returns `Gzip` if reading fails, and `InvalidWinbindexEntryFormatting` if the document itself
+/// is not a JSON map.
+pub fn read_entries(reader: impl Read, file_name: &str, repo: &str) -> Result<WinbindexFileData, WinbindexError> {
+    let classify = |e: serde_json::Error| {
+        if e.is_io() {
+            WinbindexError::Gzip
+        } else {
+            WinbindexError::InvalidWinbindexEntryFormatting(e)
+        }
+    };
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let file_data = EntryStream { file_name, repo }
+        .deserialize(&mut deserializer)
+        .map_err(classify)?;
+    deserializer.end().map_err(classify)?;
+    let malformed = file_data.anomalies.iter().filter(|a| a.kind == AnomalyKind::Malformed).count();
+    if malformed > 0 {
+        log::warn!("{repo} / {file_name}: skipped {malformed} malformed entries");
+    }
+    Ok(file_data)
 }
 
 pub struct Winbindex {
     repo_path: PathBuf,
     data_path: PathBuf,
+    revision: Option<String>,
 }
 
 impl Winbindex {
+    #[must_use]
     pub fn new(repo_path: &str, data_path: &str) -> Self {
-        return Self {
+        Self {
             repo_path: Path::new(repo_path).to_path_buf(),
             data_path: Path::new(data_path).to_path_buf(),
-        };
+            revision: None,
+        }
     }
+    /// Reads metadata from the git object database at `revision` (ie. `HEAD`, a commit id or a
+    /// branch) rather than from the working tree. This works on bare repositories and doesn't
+    /// depend on what is checked out.
+    #[must_use]
+    pub fn at_revision(mut self, revision: &str) -> Self {
+        self.revision = Some(revision.to_string());
+        self
+    }
+    /// Loads and decompresses `<data_dir>/<file_name>.json.gz`, skipping entries that are malformed
+    /// or lack `fileInfo`.
+    /// The file is parsed as it is decompressed, see [`read_entries`].
+    ///
+    /// # Errors
+    /// Fails if the file can't be opened, decompressed or parsed.
     pub fn load_file(
         &self,
         file_name: &str,
         windbindex_type: &str,
     ) -> Result<WinbindexFileData, WinbindexError> {
         let file_path = self
-            .repo_path
-            .join(&self.data_path)
+            .data_path
             .join(format!("{}{}", file_name, ".json.gz"));
-        println!("Loading file {}", file_path.to_str().ok_or_else(||WinbindexError::InvalidOsString)?);
-        let file = File::open(&file_path).map_err(|_err| WinbindexError::FileOpen(file_path))?;
-        let mut gz_buf = String::new();
-        //let read_to_end = File::read_to_end(&mut file, &mut gz_buf).map_err(|err|WinbindexError::FileRead)?;
-        let _gz_decoded = GzDecoder::new(file)
-            .read_to_string(&mut gz_buf)
-            .map_err(|_err| WinbindexError::Gzip)?;
-        let mut json: HashMap<String, WinbindexEntry> = serde_json::from_str(&gz_buf)
-            .map_err(WinbindexError::InvalidWinbindexEntryFormatting)?;
-        for (k, value) in &mut json {
-            value.repo = windbindex_type.to_string();
-            value.set_sha256(k.clone())?;
-            value.name = file_name.to_string();
+        if let Some(revision) = &self.revision {
+            log::info!(
+                "Loading file {} at {revision}",
+                file_path.to_str().ok_or(WinbindexError::InvalidOsString)?
+            );
+            let blob = self.read_blob(revision, &file_path)?;
+            read_entries(GzDecoder::new(blob.as_slice()), file_name, windbindex_type)
+        } else {
+            let file_path = self.repo_path.join(&file_path);
+            log::info!("Loading file {}", file_path.to_str().ok_or(WinbindexError::InvalidOsString)?);
+            let file = File::open(&file_path).map_err(|_err| WinbindexError::FileOpen(file_path))?;
+            read_entries(GzDecoder::new(file), file_name, windbindex_type)
         }
-        let mut cleaned_json: HashMap<String, WinbindexEntry> = HashMap::new();
+    }
+    /// Reads the blob at `path` in the tree of `revision`.
+    fn read_blob(&self, revision: &str, path: &Path) -> Result<Vec<u8>, WinbindexError> {
+        let git_error = |what: String| {
+            move |e: git2::Error| WinbindexError::GitObject(format!("{what}: {}", e.message()))
+        };
+        let repo = git2::Repository::open(&self.repo_path)
+            .map_err(git_error(format!("could not open {}", self.repo_path.display())))?;
+        let tree = repo
+            .revparse_single(revision)
+            .and_then(|object| object.peel_to_tree())
+            .map_err(git_error(format!("could not resolve {revision}")))?;
+        let blob = tree
+            .get_path(path)
+            .and_then(|entry| entry.to_object(&repo))
+            .and_then(|object| object.peel_to_blob())
+            .map_err(git_error(format!("{} at {revision}", path.display())))?;
+        Ok(blob.content().to_vec())
+    }
+}
 
-        for (k, v) in json
-            .into_iter()
-            .filter(|(ref _k, ref v)| v.file_info.is_some())
-        {
-            cleaned_json.insert(k, v);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{entry, entry_json, file_data, sha256, simple_entry, AMD64, ARM64};
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::json;
+    use std::io::Write;
+
+    fn gzip(document: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(document.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read(document: &[u8]) -> Result<WinbindexFileData, WinbindexError> {
+        read_entries(GzDecoder::new(document), "test.dll", "main")
+    }
+
+    fn update_info(title: &str) -> UpdateInfo {
+        serde_json::from_value(json!({"arch": "amd64", "build": "22621.3007", "created": 1, "title": title})).unwrap()
+    }
+
+    #[test]
+    fn title_kbs_are_whole_numbers() {
+        let info = update_info("2024-01 Safety Update (kb5034441) and Servicing Stack KB5034123, not KB");
+        assert_eq!(info.title_kbs(), ["KB5034441", "KB5034123"]);
+        assert_eq!(info.get_kb().as_deref(), Some("KB5034441"));
+        assert!(update_info("Feature update").title_kbs().is_empty());
+
+        let entry = entry("10.0.1.1", AMD64, &[("KB5034441", "22621.3007", 1)], true);
+        assert!(entry.in_update("KB5034441"));
+        assert!(entry.in_update("kb5034441"));
+        assert!(!entry.in_update("KB50"));
+        assert!(!entry.in_update("KB50344411"));
+    }
+
+    #[test]
+    fn predecessors_skip_undownloadable_entries_only_when_asked() {
+        let data = file_data([
+            simple_entry("10.0.1.1", AMD64, true),
+            simple_entry("10.0.1.2", AMD64, false),
+            simple_entry("10.0.1.3", AMD64, true),
+            simple_entry("10.0.1.4", ARM64, true),
+            entry("unknown", AMD64, &[("KB9", "22621.9", 9)], true),
+        ]);
+        let versions = |downloadable_only: bool| {
+            let mut versions: Vec<_> = data
+                .predecessors(downloadable_only)
+                .into_iter()
+                .map(|(sha256, previous)| (sha256, previous.get_version().to_string()))
+                .collect();
+            versions.sort();
+            versions
+        };
+        let predecessor = |new: &str, previous: &str| (sha256(new, AMD64), previous.to_string());
+        // Versions that can't be parsed sort above every other one.
+        assert_eq!(
+            versions(false),
+            [
+                predecessor("10.0.1.2", "10.0.1.1"),
+                predecessor("10.0.1.3", "10.0.1.2"),
+                predecessor("unknown", "10.0.1.3"),
+            ]
+        );
+        assert_eq!(
+            versions(true),
+            [
+                predecessor("10.0.1.2", "10.0.1.1"),
+                predecessor("10.0.1.3", "10.0.1.1"),
+                predecessor("unknown", "10.0.1.3"),
+            ]
+        );
+        let unknown = &data.data[&sha256("unknown", AMD64)];
+        let previous = data.find_previous_for_entry(unknown).map(|e| e.get_version().to_string());
+        assert_eq!(previous.as_deref(), Some("10.0.1.3"));
+        assert!(data.find_previous_for_entry(&simple_entry("10.0.1.5", AMD64, true)).is_none());
+    }
+
+    #[test]
+    fn bad_entries_become_anomalies() {
+        let mut missing_file_info = entry_json("10.0.1.2", AMD64, &[("KB2", "22621.2", 2)], true);
+        missing_file_info.as_object_mut().unwrap().remove("fileInfo");
+        let document = json!({
+            "a": entry_json("10.0.1.1", AMD64, &[("KB1", "22621.1", 1)], true),
+            "b": {"fileInfo": "not an object"},
+            "c": missing_file_info,
+            "d": entry_json("10.0.1.3", AMD64, &[("KB3", "22621.3", 3)], true),
+        });
+        let file_data = read(&gzip(&document.to_string())).unwrap();
+
+        let mut loaded: Vec<_> = file_data
+            .data
+            .values()
+            .map(|e| (e.get_sha256(), e.get_version().to_string()))
+            .collect();
+        loaded.sort();
+        assert_eq!(
+            loaded,
+            [(Some("a".to_string()), "10.0.1.1".to_string()), (Some("d".to_string()), "10.0.1.3".to_string())]
+        );
+        assert!(file_data.data.values().all(|e| e.name == "test.dll" && e.repo == "main"));
+        let anomalies: Vec<_> = file_data
+            .anomalies
+            .iter()
+            .map(|a| (a.sha256.as_str(), a.kind, a.error.is_some()))
+            .collect();
+        assert_eq!(anomalies, [("b", AnomalyKind::Malformed, true), ("c", AnomalyKind::MissingFileInfo, false)]);
+    }
+
+    #[test]
+    fn documents_that_are_not_a_map_are_rejected() {
+        assert!(matches!(read(&gzip("[1, 2]")), Err(WinbindexError::InvalidWinbindexEntryFormatting(_))));
+        assert!(matches!(read(&gzip("{\"a\": ")), Err(WinbindexError::InvalidWinbindexEntryFormatting(_))));
+        assert!(matches!(read(&gzip("{} {}")), Err(WinbindexError::InvalidWinbindexEntryFormatting(_))));
+    }
 
-        Ok(WinbindexFileData::new(cleaned_json))
+    #[test]
+    fn corrupt_gzip_is_rejected() {
+        assert!(matches!(read(b"not gzip at all"), Err(WinbindexError::Gzip)));
     }
 }